@@ -0,0 +1,228 @@
+use crate::tax::{exchange_date, RealizedLot, Term};
+use chrono::Datelike;
+use rust_decimal::Decimal;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilingStatus {
+    Single,
+    MarriedFilingJointly,
+    MarriedFilingSeparately,
+    HeadOfHousehold,
+}
+
+impl FilingStatus {
+    // How much of a net capital loss can be taken against ordinary income in one year
+    pub fn capital_loss_limit(self) -> Decimal {
+        match self {
+            FilingStatus::MarriedFilingSeparately => Decimal::new(1500, 0),
+            _ => Decimal::new(3000, 0),
+        }
+    }
+}
+
+// Schedule D for one tax year, with the losses carried in from the year before and out to the
+// year after
+#[derive(Debug, Clone, Default)]
+pub struct YearSummary {
+    pub year: i32,
+    // gains realized in the year, Form 6781 included
+    pub short_term: Decimal,
+    pub long_term: Decimal,
+    // losses carried over from the year before, as positive amounts
+    pub short_term_carryover_in: Decimal,
+    pub long_term_carryover_in: Decimal,
+    // Schedule D lines 7 and 15
+    pub net_short_term: Decimal,
+    pub net_long_term: Decimal,
+    // the loss taken against ordinary income, as a positive amount
+    pub deduction: Decimal,
+    pub short_term_carryover_out: Decimal,
+    pub long_term_carryover_out: Decimal,
+}
+
+impl YearSummary {
+    // Schedule D line 16
+    pub fn net(&self) -> Decimal {
+        self.net_short_term + self.net_long_term
+    }
+}
+
+// Nets every year from the first to the last one with a realized gain, or to `through` when it
+// is later, carrying what is left of a loss forward by the Capital Loss Carryover Worksheet.
+// Section 1256 gains count 40% short-term and 60% long-term in the year they are realized
+pub fn summarize<'a>(
+    lots: impl Iterator<Item = &'a RealizedLot>,
+    section_1256: impl Iterator<Item = &'a RealizedLot>,
+    status: FilingStatus,
+    through: Option<i32>,
+) -> Vec<YearSummary> {
+    let mut gains: BTreeMap<i32, (Decimal, Decimal)> = BTreeMap::new();
+    for lot in lots {
+        let gains = gains.entry(exchange_date(lot.disposed).year()).or_default();
+        match lot.term {
+            Term::Short => gains.0 += lot.gain(),
+            Term::Long => gains.1 += lot.gain(),
+        }
+    }
+    for lot in section_1256 {
+        let gains = gains.entry(exchange_date(lot.disposed).year()).or_default();
+        let short_term = lot.gain() * Decimal::new(4, 1);
+        gains.0 += short_term;
+        gains.1 += lot.gain() - short_term;
+    }
+    let (first, last) = match (gains.keys().next(), gains.keys().next_back()) {
+        (Some(first), Some(last)) => (*first, through.map_or(*last, |through| through.max(*last))),
+        _ => return Vec::new(),
+    };
+
+    let mut years = Vec::new();
+    let mut carryover = (Decimal::ZERO, Decimal::ZERO);
+    for year in first..=last {
+        let (short_term, long_term) = gains.get(&year).copied().unwrap_or_default();
+        let net_short_term = short_term - carryover.0;
+        let net_long_term = long_term - carryover.1;
+        let net = net_short_term + net_long_term;
+        let deduction = if net < Decimal::ZERO {
+            (-net).min(status.capital_loss_limit())
+        } else {
+            Decimal::ZERO
+        };
+
+        // The worksheet limits line 4 by taxable income, which is taken to be at least the
+        // deduction. A short-term loss is used up first, by the deduction and any long-term gain
+        let short_term_loss = (-net_short_term).max(Decimal::ZERO);
+        let long_term_loss = (-net_long_term).max(Decimal::ZERO);
+        let short_term_carryover_out =
+            (short_term_loss - deduction - net_long_term.max(Decimal::ZERO)).max(Decimal::ZERO);
+        let long_term_carryover_out = (long_term_loss
+            - net_short_term.max(Decimal::ZERO)
+            - (deduction - short_term_loss).max(Decimal::ZERO))
+        .max(Decimal::ZERO);
+
+        years.push(YearSummary {
+            year,
+            short_term,
+            long_term,
+            short_term_carryover_in: carryover.0,
+            long_term_carryover_in: carryover.1,
+            net_short_term,
+            net_long_term,
+            deduction,
+            short_term_carryover_out,
+            long_term_carryover_out,
+        });
+        carryover = (short_term_carryover_out, long_term_carryover_out);
+    }
+    years
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use rust_decimal_macros::dec;
+
+    // A lot sold in the middle of `year` for a gain of `gain`
+    fn lot(year: i32, term: Term, gain: Decimal) -> RealizedLot {
+        let disposed = chrono::Utc.with_ymd_and_hms(year, 6, 1, 15, 0, 0).unwrap();
+        RealizedLot {
+            symbol: "A".to_string(),
+            quantity: dec!(1),
+            acquired: disposed,
+            disposed,
+            proceeds: gain.max(Decimal::ZERO),
+            cost_basis: (-gain).max(Decimal::ZERO),
+            fees: Decimal::ZERO,
+            term,
+            adjustments: Vec::new(),
+            transferred_in: false,
+        }
+    }
+
+    fn summarize_lots(lots: &[RealizedLot], status: FilingStatus) -> Vec<YearSummary> {
+        summarize(lots.iter(), std::iter::empty(), status, None)
+    }
+
+    // deduction, and the short-term and long-term carryovers out
+    fn carryover(year: &YearSummary) -> (Decimal, Decimal, Decimal) {
+        (
+            year.deduction,
+            year.short_term_carryover_out,
+            year.long_term_carryover_out,
+        )
+    }
+
+    #[test]
+    fn short_term_loss_used_up_by_a_long_term_gain() {
+        let years = summarize_lots(
+            &[
+                lot(2021, Term::Short, dec!(-5000)),
+                lot(2021, Term::Long, dec!(3000)),
+            ],
+            FilingStatus::Single,
+        );
+        assert_eq!(years.len(), 1);
+        assert_eq!(years[0].net(), dec!(-2000));
+        assert_eq!(carryover(&years[0]), (dec!(2000), dec!(0), dec!(0)));
+    }
+
+    #[test]
+    fn long_term_loss_after_the_deduction() {
+        let years = summarize_lots(
+            &[
+                lot(2021, Term::Short, dec!(-1000)),
+                lot(2021, Term::Long, dec!(-10000)),
+            ],
+            FilingStatus::Single,
+        );
+        // the deduction takes the short-term loss first and $2,000 of the long-term one
+        assert_eq!(carryover(&years[0]), (dec!(3000), dec!(0), dec!(8000)));
+    }
+
+    #[test]
+    fn married_filing_separately_limit() {
+        let years = summarize_lots(
+            &[lot(2021, Term::Short, dec!(-5000))],
+            FilingStatus::MarriedFilingSeparately,
+        );
+        assert_eq!(carryover(&years[0]), (dec!(1500), dec!(3500), dec!(0)));
+    }
+
+    #[test]
+    fn carryover_across_a_year_without_trades() {
+        let years = summarize_lots(
+            &[
+                lot(2021, Term::Short, dec!(-10000)),
+                lot(2023, Term::Long, dec!(1000)),
+            ],
+            FilingStatus::Single,
+        );
+        let summary: Vec<_> = years
+            .iter()
+            .map(|year| (year.year, year.short_term_carryover_in, carryover(year)))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (2021, dec!(0), (dec!(3000), dec!(7000), dec!(0))),
+                (2022, dec!(7000), (dec!(3000), dec!(4000), dec!(0))),
+                (2023, dec!(4000), (dec!(3000), dec!(0), dec!(0))),
+            ]
+        );
+    }
+
+    #[test]
+    fn carryover_into_a_year_after_the_last_trade() {
+        let lots = [lot(2021, Term::Short, dec!(-5000))];
+        let years = summarize(
+            lots.iter(),
+            std::iter::empty(),
+            FilingStatus::Single,
+            Some(2022),
+        );
+        assert_eq!(years.len(), 2);
+        assert_eq!(years[1].short_term_carryover_in, dec!(2000));
+        assert_eq!(carryover(&years[1]), (dec!(2000), dec!(0), dec!(0)));
+    }
+}
@@ -1,9 +1,16 @@
 use chrono;
+use rust_decimal::Decimal;
+use serde::de::Error;
 use serde::Deserialize;
 use std::collections::HashMap;
 
 pub type Trades = HashMap<String, Vec<Trade>>;
 pub type OptionTrades = HashMap<String, Vec<OptionTrade>>;
+pub type LotSelections = HashMap<String, Vec<LotSelection>>;
+pub type CorporateActions = HashMap<String, Vec<CorporateAction>>;
+pub type OptionMarks = HashMap<String, Vec<OptionMark>>;
+pub type OpeningLots = HashMap<String, Vec<OpeningLot>>;
+pub type Prices = HashMap<String, Price>;
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Trade {
@@ -11,16 +18,22 @@ pub struct Trade {
     pub date: chrono::DateTime<chrono::Utc>,
     pub order_type: String,
     pub side: String,
-    pub fees: f64,
-    pub quantity: f64,
-    pub average_price: f64,
+    pub fees: Decimal,
+    pub quantity: Decimal,
+    pub average_price: Decimal,
+    // Line of the CSV the trade is on, counting the header as line 1
+    #[serde(skip)]
+    pub row: u64,
 }
 
 pub fn parse(csv: &str) -> Result<Trades, csv::Error> {
     let mut reader = csv::Reader::from_reader(csv.as_bytes());
+    let headers = reader.headers()?.clone();
     let mut trades = HashMap::new();
-    for trade in reader.deserialize() {
-        let trade: Trade = trade?;
+    for record in reader.records() {
+        let record = record?;
+        let mut trade: Trade = record.deserialize(Some(&headers))?;
+        trade.row = record.position().map_or(0, |position| position.line());
         trades
             .entry(trade.symbol.clone())
             .or_insert(Vec::new())
@@ -33,24 +46,33 @@ pub fn parse(csv: &str) -> Result<Trades, csv::Error> {
 pub struct OptionTrade {
     pub chain_symbol: String,
     pub expiration_date: chrono::NaiveDate,
-    pub strike_price: f64,
+    pub strike_price: Decimal,
     pub option_type: String,
     pub side: String,
     pub order_created_at: chrono::DateTime<chrono::Utc>,
     pub direction: String,
-    pub order_quantity: f64,
+    pub order_quantity: Decimal,
     pub order_type: String,
     pub opening_strategy: Option<String>,
     pub closing_strategy: Option<String>,
-    pub price: f64,
-    pub processed_quantity: f64,
+    pub price: Decimal,
+    pub processed_quantity: Decimal,
+    // Shares per contract when it is not the standard 100, like mini or adjusted contracts
+    #[serde(default)]
+    pub multiplier: Option<Decimal>,
+    // Line of the CSV the trade is on, counting the header as line 1
+    #[serde(skip)]
+    pub row: u64,
 }
 
 pub fn parse_options(csv: &str) -> Result<OptionTrades, csv::Error> {
     let mut reader = csv::Reader::from_reader(csv.as_bytes());
+    let headers = reader.headers()?.clone();
     let mut trades = HashMap::new();
-    for trade in reader.deserialize() {
-        let trade: OptionTrade = trade?;
+    for record in reader.records() {
+        let record = record?;
+        let mut trade: OptionTrade = record.deserialize(Some(&headers))?;
+        trade.row = record.position().map_or(0, |position| position.line());
         trades
             .entry(trade.chain_symbol.clone())
             .or_insert(Vec::new())
@@ -58,3 +80,224 @@ pub fn parse_options(csv: &str) -> Result<OptionTrades, csv::Error> {
     }
     Ok(trades)
 }
+
+// A specific identification: the sell of `symbol` on `sold` consumes `quantity` shares of the
+// lots bought on `acquired`, or all of them when there is no quantity. A sell's selections are
+// used up in the order they are listed
+#[derive(Debug, Clone, Deserialize)]
+pub struct LotSelection {
+    pub symbol: String,
+    pub sold: chrono::NaiveDate,
+    pub acquired: chrono::NaiveDate,
+    #[serde(default)]
+    pub quantity: Option<Decimal>,
+}
+
+pub fn parse_lot_selections(csv: &str) -> Result<LotSelections, csv::Error> {
+    let mut reader = csv::Reader::from_reader(csv.as_bytes());
+    let mut selections = HashMap::new();
+    for selection in reader.deserialize() {
+        let selection: LotSelection = selection?;
+        selections
+            .entry(selection.symbol.clone())
+            .or_insert(Vec::new())
+            .push(selection);
+    }
+    Ok(selections)
+}
+
+// What happened to a symbol in a corporate action
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ActionKind {
+    // Every share held becomes `ratio` shares, e.g. 4 for a 4-for-1 split or 0.1 for a 1-for-10
+    // reverse split
+    #[default]
+    Split,
+    // The shares are now traded as `new_symbol`, `ratio` new shares for each old one
+    Rename,
+    // The company was bought. For stock every share becomes `ratio` shares of `new_symbol`, for
+    // cash (no `new_symbol`) every share is sold for `cash`
+    Merger,
+    // Every share also gets `ratio` shares of `new_symbol`, which take `allocation` percent of
+    // the basis
+    Spinoff,
+}
+
+// A corporate action on `symbol` taking effect at the open on `date`. When it leaves a fraction
+// of a share, the fraction is paid out at `cash` per share
+#[derive(Debug, Clone, Deserialize)]
+pub struct CorporateAction {
+    pub symbol: String,
+    pub date: chrono::NaiveDate,
+    #[serde(default)]
+    pub action: ActionKind,
+    pub ratio: Decimal,
+    #[serde(default)]
+    pub new_symbol: Option<String>,
+    #[serde(default)]
+    pub allocation: Option<Decimal>,
+    #[serde(default)]
+    pub cash: Option<Decimal>,
+    // Line of the CSV the action is on, counting the header as line 1
+    #[serde(skip)]
+    pub row: u64,
+}
+
+// An error for a row that parses but cannot be right, pointing at its line
+fn invalid_row(record: &csv::StringRecord, message: &str) -> csv::Error {
+    let line = record.position().map_or(0, |position| position.line());
+    csv::Error::from(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("line {}: {}", line, message),
+    ))
+}
+
+pub fn parse_corporate_actions(csv: &str) -> Result<CorporateActions, csv::Error> {
+    let mut reader = csv::Reader::from_reader(csv.as_bytes());
+    let headers = reader.headers()?.clone();
+    let mut actions = HashMap::new();
+    for record in reader.records() {
+        let record = record?;
+        let mut action: CorporateAction = record.deserialize(Some(&headers))?;
+        action.row = record.position().map_or(0, |position| position.line());
+        // a missing amount is never taken to be zero, which would quietly give away the basis.
+        // A cash merger is the one action without a ratio
+        let cash_merger = action.action == ActionKind::Merger && action.new_symbol.is_none();
+        if cash_merger && action.cash.is_none() {
+            return Err(invalid_row(
+                &record,
+                "a cash merger needs the cash per share",
+            ));
+        }
+        if !cash_merger && action.ratio <= Decimal::ZERO {
+            return Err(invalid_row(&record, "the ratio has to be more than zero"));
+        }
+        if action.action == ActionKind::Spinoff && action.allocation.is_none() {
+            return Err(invalid_row(
+                &record,
+                "a spin-off needs the allocation of basis",
+            ));
+        }
+        actions
+            .entry(action.symbol.clone())
+            .or_insert(Vec::new())
+            .push(action);
+    }
+    Ok(actions)
+}
+
+// What one contract was worth per share at the close on `date`, for marking Section 1256
+// contracts to market at year end
+#[derive(Debug, Clone, Deserialize)]
+pub struct OptionMark {
+    pub chain_symbol: String,
+    pub expiration_date: chrono::NaiveDate,
+    pub strike_price: Decimal,
+    pub option_type: String,
+    pub date: chrono::NaiveDate,
+    pub price: Decimal,
+}
+
+pub fn parse_option_marks(csv: &str) -> Result<OptionMarks, csv::Error> {
+    let mut reader = csv::Reader::from_reader(csv.as_bytes());
+    let mut marks = HashMap::new();
+    for mark in reader.deserialize() {
+        let mark: OptionMark = mark?;
+        marks
+            .entry(mark.chain_symbol.clone())
+            .or_insert(Vec::new())
+            .push(mark);
+    }
+    Ok(marks)
+}
+
+// Shares already held when the history starts, like a position transferred in from another broker
+#[derive(Debug, Clone, Deserialize)]
+pub struct OpeningLot {
+    pub symbol: String,
+    pub quantity: Decimal,
+    pub acquired: chrono::NaiveDate,
+    pub cost_basis: Decimal,
+}
+
+pub fn parse_opening_lots(csv: &str) -> Result<OpeningLots, csv::Error> {
+    let mut reader = csv::Reader::from_reader(csv.as_bytes());
+    let headers = reader.headers()?.clone();
+    let mut lots = HashMap::new();
+    for record in reader.records() {
+        let record = record?;
+        let lot: OpeningLot = record.deserialize(Some(&headers))?;
+        if lot.quantity <= Decimal::ZERO {
+            return Err(invalid_row(
+                &record,
+                "the quantity has to be more than zero",
+            ));
+        }
+        lots.entry(lot.symbol.clone())
+            .or_insert(Vec::new())
+            .push(lot);
+    }
+    Ok(lots)
+}
+
+// What one share of `symbol` is worth now, or as of `date` when the snapshot is older
+#[derive(Debug, Clone, Deserialize)]
+pub struct Price {
+    pub symbol: String,
+    pub price: Decimal,
+    #[serde(default)]
+    pub date: Option<chrono::NaiveDate>,
+}
+
+pub fn parse_prices(csv: &str) -> Result<Prices, csv::Error> {
+    let mut reader = csv::Reader::from_reader(csv.as_bytes());
+    let mut prices = HashMap::new();
+    for price in reader.deserialize() {
+        let price: Price = price?;
+        prices.insert(price.symbol.clone(), price);
+    }
+    Ok(prices)
+}
+
+// The same prices as JSON, either `{"AAPL": 190.5, ...}` or
+// `{"date": "2024-06-20", "prices": {"AAPL": 190.5, ...}}` for a snapshot as of a date
+pub fn parse_prices_json(json: &str) -> Result<Prices, serde_json::Error> {
+    let value: serde_json::Value = serde_json::from_str(json)?;
+    let date = match value.get("date") {
+        Some(date) => Some(serde_json::from_value(date.clone())?),
+        None => None,
+    };
+    let entries = match value.get("prices").unwrap_or(&value) {
+        serde_json::Value::Object(entries) => entries,
+        _ => return Err(serde_json::Error::custom("expected an object of prices")),
+    };
+    let mut prices = HashMap::new();
+    for (symbol, price) in entries {
+        if value.get("prices").is_none() && symbol == "date" {
+            continue;
+        }
+        let price = match price {
+            serde_json::Value::Number(price) => price.to_string(),
+            serde_json::Value::String(price) => price.clone(),
+            _ => {
+                return Err(serde_json::Error::custom(format!(
+                    "no price for {}",
+                    symbol
+                )))
+            }
+        };
+        let price = price
+            .parse()
+            .map_err(|_| serde_json::Error::custom(format!("bad price for {}", symbol)))?;
+        prices.insert(
+            symbol.clone(),
+            Price {
+                symbol: symbol.clone(),
+                price,
+                date,
+            },
+        );
+    }
+    Ok(prices)
+}
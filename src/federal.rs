@@ -0,0 +1,264 @@
+use crate::capital_gains::{FilingStatus, YearSummary};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+
+// The federal tables of every tax year we have, one file per year
+const TABLES: &[(i32, &str)] = &[
+    (2021, include_str!("../data/federal/2021.csv")),
+    (2022, include_str!("../data/federal/2022.csv")),
+    (2023, include_str!("../data/federal/2023.csv")),
+    (2024, include_str!("../data/federal/2024.csv")),
+    (2025, include_str!("../data/federal/2025.csv")),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Kind {
+    StandardDeduction,
+    // rates on ordinary taxable income
+    Ordinary,
+    // rates on long-term gains, by the taxable income they are stacked on top of
+    LongTerm,
+    // Net Investment Income Tax on modified AGI over the threshold
+    Niit,
+}
+
+// One line of a table: `rate` applies to income over `over`. For the standard deduction `over`
+// is the deduction itself
+#[derive(Debug, Clone, Deserialize)]
+struct TableRow {
+    filing_status: String,
+    kind: Kind,
+    over: Decimal,
+    rate: Decimal,
+}
+
+// The federal tables of one year for one filing status
+#[derive(Debug, Clone, Default)]
+struct Brackets {
+    standard_deduction: Decimal,
+    ordinary: Vec<(Decimal, Decimal)>,
+    long_term: Vec<(Decimal, Decimal)>,
+    niit: (Decimal, Decimal),
+}
+
+// Every year there is a table for
+pub fn years() -> impl Iterator<Item = i32> {
+    TABLES.iter().map(|(year, _)| *year)
+}
+
+pub fn status_key(status: FilingStatus) -> &'static str {
+    match status {
+        FilingStatus::Single => "single",
+        FilingStatus::MarriedFilingJointly => "joint",
+        FilingStatus::MarriedFilingSeparately => "separate",
+        FilingStatus::HeadOfHousehold => "head",
+    }
+}
+
+// The tables for `year`, or the latest year before it when they are not out yet, and which year
+// they are from
+fn brackets(year: i32, status: FilingStatus) -> Result<Option<(i32, Brackets)>, csv::Error> {
+    let (year, table) = match TABLES
+        .iter()
+        .rev()
+        .find(|(table_year, _)| *table_year <= year)
+    {
+        Some(table) => table,
+        None => return Ok(None),
+    };
+    let mut brackets = Brackets::default();
+    let mut reader = csv::Reader::from_reader(table.as_bytes());
+    for row in reader.deserialize() {
+        let row: TableRow = row?;
+        if row.filing_status != status_key(status) {
+            continue;
+        }
+        match row.kind {
+            Kind::StandardDeduction => brackets.standard_deduction = row.over,
+            Kind::Ordinary => brackets.ordinary.push((row.over, row.rate)),
+            Kind::LongTerm => brackets.long_term.push((row.over, row.rate)),
+            Kind::Niit => brackets.niit = (row.over, row.rate),
+        }
+    }
+    Ok(Some((*year, brackets)))
+}
+
+// Tax on the part of taxable income from `from` up to `to`, by the bracket each dollar falls in
+pub fn progressive(brackets: &[(Decimal, Decimal)], from: Decimal, to: Decimal) -> Decimal {
+    let mut tax = Decimal::ZERO;
+    for (i, (over, rate)) in brackets.iter().enumerate() {
+        let lower = from.max(*over);
+        let upper = match brackets.get(i + 1) {
+            Some((next, _)) => to.min(*next),
+            None => to,
+        };
+        if upper > lower {
+            tax += (upper - lower) * rate;
+        }
+    }
+    tax
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Estimate {
+    // the tax year whose tables were used
+    pub table_year: i32,
+    // adjusted gross income, gains and the capital loss deduction included
+    pub income: Decimal,
+    pub taxable_income: Decimal,
+    pub ordinary_tax: Decimal,
+    pub long_term_tax: Decimal,
+    pub niit: Decimal,
+    // how much of the total there would not be without the gains and losses
+    pub on_gains: Decimal,
+    // percent owed on one more dollar of short-term and of long-term gain
+    pub marginal_short_term: Decimal,
+    pub marginal_long_term: Decimal,
+}
+
+impl Estimate {
+    pub fn total(&self) -> Decimal {
+        self.ordinary_tax + self.long_term_tax + self.niit
+    }
+
+    // Percent of income owed in tax
+    pub fn effective_rate(&self) -> Decimal {
+        if self.income > Decimal::ZERO {
+            self.total() / self.income * Decimal::ONE_HUNDRED
+        } else {
+            Decimal::ZERO
+        }
+    }
+}
+
+// Form 1040 with the Qualified Dividends and Capital Gain Tax Worksheet, on the standard
+// deduction. Long-term gains are taxed at their own rates on top of the ordinary income, and
+// short-term gains as ordinary income
+fn compute(
+    brackets: &Brackets,
+    limit: Decimal,
+    ordinary_income: Decimal,
+    net_short_term: Decimal,
+    net_long_term: Decimal,
+) -> Estimate {
+    let net = net_short_term + net_long_term;
+    // a net loss only takes up to the limit off ordinary income, and only a net long-term gain
+    // left after the short-term losses gets the long-term rates
+    let (capital, long_term) = if net < Decimal::ZERO {
+        (-(-net).min(limit), Decimal::ZERO)
+    } else {
+        (net, net_long_term.min(net).max(Decimal::ZERO))
+    };
+    let income = ordinary_income + capital;
+    let taxable_income = (income - brackets.standard_deduction).max(Decimal::ZERO);
+    let long_term = long_term.min(taxable_income);
+    let ordinary = taxable_income - long_term;
+    let (threshold, rate) = brackets.niit;
+    Estimate {
+        income,
+        taxable_income,
+        ordinary_tax: progressive(&brackets.ordinary, Decimal::ZERO, ordinary),
+        long_term_tax: progressive(&brackets.long_term, ordinary, taxable_income),
+        niit: net
+            .max(Decimal::ZERO)
+            .min((income - threshold).max(Decimal::ZERO))
+            * rate,
+        ..Estimate::default()
+    }
+}
+
+// The federal tax owed for a year of gains on top of other ordinary income, like wages and
+// interest. None when there are no tables for the year
+pub fn estimate(
+    summary: &YearSummary,
+    status: FilingStatus,
+    ordinary_income: Decimal,
+) -> Result<Option<Estimate>, csv::Error> {
+    let (table_year, brackets) = match brackets(summary.year, status)? {
+        Some(brackets) => brackets,
+        None => return Ok(None),
+    };
+    let limit = status.capital_loss_limit();
+    let (short_term, long_term) = (summary.net_short_term, summary.net_long_term);
+    let mut estimate = compute(&brackets, limit, ordinary_income, short_term, long_term);
+    let total = estimate.total();
+    let without_gains = compute(
+        &brackets,
+        limit,
+        ordinary_income,
+        Decimal::ZERO,
+        Decimal::ZERO,
+    );
+    let one_more = |short_term, long_term| {
+        (compute(&brackets, limit, ordinary_income, short_term, long_term).total() - total)
+            * Decimal::ONE_HUNDRED
+    };
+    estimate.table_year = table_year;
+    estimate.on_gains = total - without_gains.total();
+    estimate.marginal_short_term = one_more(short_term + Decimal::ONE, long_term);
+    estimate.marginal_long_term = one_more(short_term, long_term + Decimal::ONE);
+    Ok(Some(estimate))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    fn estimate_2024(
+        ordinary_income: Decimal,
+        short_term: Decimal,
+        long_term: Decimal,
+    ) -> Estimate {
+        let summary = YearSummary {
+            year: 2024,
+            net_short_term: short_term,
+            net_long_term: long_term,
+            ..Default::default()
+        };
+        estimate(&summary, FilingStatus::Single, ordinary_income)
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn long_term_gain_stacked_on_ordinary_income() {
+        let estimate = estimate_2024(dec!(100000), dec!(0), dec!(50000));
+        assert_eq!(estimate.table_year, 2024);
+        assert_eq!(estimate.taxable_income, dec!(135400));
+        assert_eq!(estimate.ordinary_tax, dec!(13841));
+        assert_eq!(estimate.long_term_tax, dec!(7500));
+        assert_eq!(estimate.niit, dec!(0));
+        assert_eq!(estimate.on_gains, dec!(7500));
+        assert_eq!(
+            (estimate.marginal_short_term, estimate.marginal_long_term),
+            (dec!(22), dec!(15))
+        );
+    }
+
+    #[test]
+    fn net_investment_income_tax_over_the_threshold() {
+        // only the $30,000 of income over the $200,000 threshold is taxed
+        let estimate = estimate_2024(dec!(180000), dec!(0), dec!(50000));
+        assert_eq!(estimate.niit, dec!(1140));
+        assert_eq!(estimate.marginal_long_term, dec!(18.8));
+    }
+
+    #[test]
+    fn short_term_gain_taxed_as_ordinary_income() {
+        let estimate = estimate_2024(dec!(100000), dec!(10000), dec!(0));
+        // $95,400 taxable, all of it at the ordinary rates
+        assert_eq!(estimate.long_term_tax, dec!(0));
+        assert_eq!(estimate.ordinary_tax, dec!(16041));
+        assert_eq!(estimate.on_gains, dec!(2200));
+    }
+
+    #[test]
+    fn loss_limited_against_ordinary_income() {
+        let estimate = estimate_2024(dec!(100000), dec!(-10000), dec!(0));
+        // only $3,000 of the loss comes off, at 22%
+        assert_eq!(estimate.income, dec!(97000));
+        assert_eq!(estimate.on_gains, dec!(-660));
+    }
+}
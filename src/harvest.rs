@@ -0,0 +1,111 @@
+use crate::csv_parser::{Prices, Trades};
+use crate::tax::{
+    exchange_close, exchange_date, holding_term, in_wash_window, long_term_from, AllInfo, OpenLot,
+    Term,
+};
+use rust_decimal::Decimal;
+
+// A held lot worth less than it cost, which could be sold to realize the loss
+#[derive(Debug, Clone)]
+pub struct Recommendation {
+    pub symbol: String,
+    pub lot: OpenLot,
+    pub price: Decimal,
+    // the loss selling the lot at `price` would realize, as a positive amount
+    pub loss: Decimal,
+    // the term of the loss if sold on the day of the price
+    pub term: Term,
+    // a buy of the symbol in the 30 days up to the day of the price, which would wash the loss
+    // if it is sold then
+    pub wash_buy: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+// Every held lot with an unrealized loss at the given prices, the largest loss first. A price
+// counts as of its own date, or today when it has none
+pub fn recommendations(
+    info: &AllInfo,
+    trades: &Trades,
+    prices: &Prices,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Vec<Recommendation> {
+    let mut recommendations = Vec::new();
+    for (symbol, position) in info {
+        let (price, as_of) = match prices.get(symbol) {
+            Some(price) => (
+                price.price,
+                price.date.unwrap_or_else(|| exchange_date(now)),
+            ),
+            None => continue,
+        };
+        let sold = exchange_close(as_of);
+        for lot in &position.open {
+            let loss = lot.cost_basis - price * lot.quantity;
+            if loss <= Decimal::ZERO {
+                continue;
+            }
+            // the lot's own buy does not replace itself. A wash sale replacement is acquired
+            // before its buy, so the buy is told apart by its line rather than its date
+            let wash_buy = trades
+                .get(symbol)
+                .into_iter()
+                .flatten()
+                .filter(|trade| {
+                    trade.side == "buy"
+                        && Some(trade.row) != lot.row
+                        && trade.date <= sold
+                        && in_wash_window(sold, trade.date)
+                })
+                .map(|trade| trade.date)
+                .max();
+            recommendations.push(Recommendation {
+                symbol: symbol.clone(),
+                lot: lot.clone(),
+                price,
+                loss,
+                term: holding_term(lot.acquired, sold),
+                wash_buy,
+            });
+        }
+    }
+    recommendations.sort_by_key(|recommendation| std::cmp::Reverse(recommendation.loss));
+    recommendations
+}
+
+// A held lot that turns long-term soon
+#[derive(Debug, Clone)]
+pub struct Upcoming {
+    pub symbol: String,
+    pub lot: OpenLot,
+    // the first day selling it is long-term
+    pub long_term_from: chrono::NaiveDate,
+    // the gain selling it at the uploaded price would realize, when there is a price
+    pub gain: Option<Decimal>,
+}
+
+// Every held lot still short-term now that turns long-term within `days` days, the soonest first
+pub fn upcoming_long_term(
+    info: &AllInfo,
+    prices: &Prices,
+    now: chrono::DateTime<chrono::Utc>,
+    days: i64,
+) -> Vec<Upcoming> {
+    let today = exchange_date(now);
+    let mut upcoming = Vec::new();
+    for (symbol, position) in info {
+        let price = prices.get(symbol).map(|price| price.price);
+        for lot in &position.open {
+            let from = long_term_from(lot.acquired);
+            if from <= today || (from - today).num_days() > days {
+                continue;
+            }
+            upcoming.push(Upcoming {
+                symbol: symbol.clone(),
+                lot: lot.clone(),
+                long_term_from: from,
+                gain: price.map(|price| price * lot.quantity - lot.cost_basis),
+            });
+        }
+    }
+    upcoming.sort_by_key(|upcoming| upcoming.long_term_from);
+    upcoming
+}
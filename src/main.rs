@@ -1,29 +1,59 @@
 use yew::prelude::*;
 
+mod capital_gains;
 pub mod csv_parser;
+mod federal;
+mod harvest;
 mod option_tax;
+mod report;
+mod state;
 mod tax;
+mod unrealized;
 
-use web_sys::{Event, HtmlInputElement};
+use web_sys::{Event, HtmlInputElement, HtmlTextAreaElement};
 use yew::{html, html::TargetCast, Component, Context, Html};
 
 use gloo_file::callbacks::FileReader;
-use gloo_file::File;
+use gloo_file::{Blob, File, ObjectUrl};
 
-use chrono::TimeZone;
+use chrono::Datelike;
+use rust_decimal::Decimal;
 
-enum Investment {
+#[derive(Clone, Copy)]
+enum Upload {
     Stock,
     Crypto,
     Options,
+    LotSelections,
+    CorporateActions,
+    OptionMarks,
+    OpeningLots,
+    Prices,
 }
 
 enum Msg {
-    Loaded(String, String, Investment),
-    File(File, Investment),
+    Loaded(String, String, Upload),
+    File(File, Upload),
     Err(String),
     UpdateSymbolFilter(String),
     UpdateYearFilter(String),
+    UpdateLotMethod(String),
+    UpdateRounding(String),
+    UpdateShortSales(bool),
+    UpdateSection1256(String),
+    UpdateOpeningLots(String),
+    UpdateFilingStatus(String),
+    UpdateOrdinaryIncome(String),
+    UpdateState(String),
+    UpdateLongTermDays(String),
+}
+
+// What the engines worked out for each history that is loaded
+#[derive(Default)]
+struct Engines {
+    stocks: Option<tax::AllInfo>,
+    crypto: Option<tax::AllInfo>,
+    options: Option<option_tax::AllOptionInfo>,
 }
 
 struct Model {
@@ -36,10 +66,47 @@ struct Model {
     option_tax_info: Option<option_tax::AllOptionInfo>,
     option_csv_data: Option<csv_parser::OptionTrades>,
 
+    lot_selections: csv_parser::LotSelections,
+    corporate_actions: csv_parser::CorporateActions,
+    option_marks: csv_parser::OptionMarks,
+    // comma separated underlyings whose options are Section 1256 contracts
+    section_1256: String,
+    form_6781: report::Form6781,
+    // shares held before the stock history starts, as CSV so they can be typed in or uploaded
+    opening_lots_csv: String,
+    opening_lots: csv_parser::OpeningLots,
+    // why the typed lots do not parse, kept apart so fixing them leaves other errors alone
+    opening_lots_err: String,
+    filing_status: capital_gains::FilingStatus,
+    // every year in the histories, netted with the losses carried over
+    years: Vec<capital_gains::YearSummary>,
+    // wages, interest and other income taxed at the ordinary rates
+    ordinary_income: Decimal,
+    // federal tax for the year filtered on, or the last year in the histories
+    estimate: Option<federal::Estimate>,
+    state: state::State,
+    state_estimate: Option<state::Estimate>,
+    // the year the estimates are for, also when there are no tables for it
+    estimate_year: Option<i32>,
+    // what a share of each symbol is worth now
+    prices: csv_parser::Prices,
+    recommendations: Vec<harvest::Recommendation>,
+    // how many days ahead to look for lots turning long-term
+    long_term_days: i64,
+    upcoming: Vec<harvest::Upcoming>,
+    unrealized: Vec<unrealized::Unrealized>,
+
+    // Form 8949 as CSV and as a printable page
+    report_urls: Option<(ObjectUrl, ObjectUrl)>,
+
     reader: Option<FileReader>,
     symbol_filter: String,
     err: String,
-    year: Option<chrono::DateTime<chrono::Utc>>,
+    year: Option<tax::TaxYear>,
+    lot_method: tax::LotMethod,
+    // whether a sell of shares not held opens a short position
+    short_sales: bool,
+    rounding: report::Rounding,
 }
 
 impl Component for Model {
@@ -57,55 +124,102 @@ impl Component for Model {
             option_tax_info: None,
             option_csv_data: None,
 
+            lot_selections: csv_parser::LotSelections::new(),
+            corporate_actions: csv_parser::CorporateActions::new(),
+            option_marks: csv_parser::OptionMarks::new(),
+            section_1256: "SPX, NDX, RUT, VIX".to_string(),
+            form_6781: report::Form6781::default(),
+            opening_lots_csv: "symbol,quantity,acquired,cost_basis\n".to_string(),
+            opening_lots: csv_parser::OpeningLots::new(),
+            opening_lots_err: String::new(),
+            filing_status: capital_gains::FilingStatus::Single,
+            years: Vec::new(),
+            ordinary_income: Decimal::ZERO,
+            estimate: None,
+            state: state::State::NoStateTax,
+            state_estimate: None,
+            estimate_year: None,
+            prices: csv_parser::Prices::new(),
+            recommendations: Vec::new(),
+            long_term_days: 60,
+            upcoming: Vec::new(),
+            unrealized: Vec::new(),
+
+            report_urls: None,
+
             reader: None,
             symbol_filter: String::new(),
             err: String::new(),
             year: None,
+            lot_method: tax::LotMethod::Fifo,
+            short_sales: false,
+            rounding: report::Rounding::Cents,
         }
     }
 
     fn update(&mut self, ctx: &Context<Self>, msg: Self::Message) -> bool {
         match msg {
-            Msg::Loaded(_fname, data, investment) => {
-                match investment {
-                    Investment::Stock => match csv_parser::parse(&data) {
-                        Ok(trades) => {
-                            self.stock_csv_data = Some(trades.clone());
-                            let stock_tax_info =
-                                tax::parse(&trades, self.symbol_filter.clone(), self.year);
-                            self.stock_tax_info = Some(stock_tax_info);
+            Msg::Loaded(fname, data, upload) => {
+                match upload {
+                    Upload::Stock => match csv_parser::parse(&data) {
+                        Ok(trades) => self.stock_csv_data = Some(trades),
+                        Err(csv_err) => {
+                            self.err = format!("{:?}", csv_err);
                         }
+                    },
+                    Upload::Crypto => match csv_parser::parse(&data) {
+                        Ok(trades) => self.crypto_csv_data = Some(trades),
                         Err(csv_err) => {
                             self.err = format!("{:?}", csv_err);
                         }
                     },
-                    Investment::Crypto => match csv_parser::parse(&data) {
-                        Ok(trades) => {
-                            self.crypto_csv_data = Some(trades.clone());
-                            let crypto_tax_info =
-                                tax::parse(&trades, self.symbol_filter.clone(), self.year);
-                            self.crypto_tax_info = Some(crypto_tax_info);
+                    Upload::Options => match csv_parser::parse_options(&data) {
+                        Ok(trades) => self.option_csv_data = Some(trades),
+                        Err(csv_err) => {
+                            self.err = format!("{:?}", csv_err);
                         }
+                    },
+                    Upload::LotSelections => match csv_parser::parse_lot_selections(&data) {
+                        Ok(selections) => self.lot_selections = selections,
                         Err(csv_err) => {
                             self.err = format!("{:?}", csv_err);
                         }
                     },
-                    Investment::Options => match csv_parser::parse_options(&data) {
-                        Ok(trades) => {
-                            self.option_csv_data = Some(trades.clone());
-                            let option_tax_info =
-                                option_tax::parse(&trades, self.symbol_filter.clone(), self.year);
-                            self.option_tax_info = Some(option_tax_info);
+                    Upload::CorporateActions => match csv_parser::parse_corporate_actions(&data) {
+                        Ok(actions) => self.corporate_actions = actions,
+                        Err(csv_err) => {
+                            self.err = format!("{:?}", csv_err);
+                        }
+                    },
+                    Upload::OptionMarks => match csv_parser::parse_option_marks(&data) {
+                        Ok(marks) => self.option_marks = marks,
+                        Err(csv_err) => {
+                            self.err = format!("{:?}", csv_err);
+                        }
+                    },
+                    Upload::Prices if fname.ends_with(".json") => {
+                        match csv_parser::parse_prices_json(&data) {
+                            Ok(prices) => self.prices = prices,
+                            Err(json_err) => {
+                                self.err = format!("{:?}", json_err);
+                            }
                         }
+                    }
+                    Upload::Prices => match csv_parser::parse_prices(&data) {
+                        Ok(prices) => self.prices = prices,
                         Err(csv_err) => {
                             self.err = format!("{:?}", csv_err);
                         }
                     },
+                    Upload::OpeningLots => {
+                        self.update_opening_lots(data);
+                    }
                 }
+                self.recompute();
                 self.reader = None;
                 true
             }
-            Msg::File(file, investment) => {
+            Msg::File(file, upload) => {
                 let file_name = file.name();
                 let task = {
                     let file_name = file_name.clone();
@@ -114,7 +228,7 @@ impl Component for Model {
                         link.send_message(Msg::Loaded(
                             file_name,
                             res.unwrap_or_else(|e| e.to_string()),
-                            investment,
+                            upload,
                         ))
                     })
                 };
@@ -123,47 +237,90 @@ impl Component for Model {
             }
             Msg::UpdateSymbolFilter(c) => {
                 self.symbol_filter = c.to_uppercase();
-                if let Some(trades) = &self.stock_csv_data {
-                    let stock_tax_info = tax::parse(&trades, self.symbol_filter.clone(), self.year);
-                    self.stock_tax_info = Some(stock_tax_info);
-                }
-                if let Some(trades) = &self.crypto_csv_data {
-                    let crypto_tax_info =
-                        tax::parse(&trades, self.symbol_filter.clone(), self.year);
-                    self.crypto_tax_info = Some(crypto_tax_info);
-                }
-                if let Some(trades) = &self.option_csv_data {
-                    let option_tax_info =
-                        option_tax::parse(&trades, self.symbol_filter.clone(), self.year);
-                    self.option_tax_info = Some(option_tax_info);
-                }
-
+                self.recompute();
                 true
             }
             Msg::UpdateYearFilter(y) => {
                 if y == "none" {
                     self.year = None;
-                } else {
-                    if let Some(year) = y.parse().ok() {
-                        self.year = Some(chrono::Utc.ymd(year, 1, 1).and_hms(0, 0, 0));
-                    }
+                } else if let Ok(year) = y.parse() {
+                    self.year = tax::TaxYear::calendar(year, tax::EXCHANGE_TIMEZONE);
                 }
-                if let Some(trades) = &self.stock_csv_data {
-                    let stock_tax_info = tax::parse(&trades, self.symbol_filter.clone(), self.year);
-                    self.stock_tax_info = Some(stock_tax_info);
-                }
-                if let Some(trades) = &self.crypto_csv_data {
-                    let crypto_tax_info =
-                        tax::parse(&trades, self.symbol_filter.clone(), self.year);
-                    self.crypto_tax_info = Some(crypto_tax_info);
+                self.recompute();
+                true
+            }
+            Msg::UpdateLotMethod(m) => {
+                self.lot_method = match m.as_str() {
+                    "lifo" => tax::LotMethod::Lifo,
+                    "hifo" => tax::LotMethod::Hifo,
+                    "lofo" => tax::LotMethod::Lofo,
+                    "specific" => tax::LotMethod::Specific,
+                    _ => tax::LotMethod::Fifo,
+                };
+                self.recompute();
+                true
+            }
+            Msg::UpdateRounding(r) => {
+                self.rounding = match r.as_str() {
+                    "dollars" => report::Rounding::WholeDollars,
+                    _ => report::Rounding::Cents,
+                };
+                self.recompute();
+                true
+            }
+            Msg::UpdateShortSales(short_sales) => {
+                self.short_sales = short_sales;
+                self.recompute();
+                true
+            }
+            Msg::UpdateSection1256(s) => {
+                self.section_1256 = s;
+                self.recompute();
+                true
+            }
+            Msg::UpdateFilingStatus(status) => {
+                self.filing_status = match status.as_str() {
+                    "joint" => capital_gains::FilingStatus::MarriedFilingJointly,
+                    "separate" => capital_gains::FilingStatus::MarriedFilingSeparately,
+                    "head" => capital_gains::FilingStatus::HeadOfHousehold,
+                    _ => capital_gains::FilingStatus::Single,
+                };
+                self.recompute();
+                true
+            }
+            Msg::UpdateOrdinaryIncome(income) => {
+                if let Ok(income) = income.trim().replace(',', "").parse() {
+                    self.ordinary_income = income;
+                } else if income.trim().is_empty() {
+                    self.ordinary_income = Decimal::ZERO;
                 }
-                if let Some(trades) = &self.option_csv_data {
-                    let option_tax_info =
-                        option_tax::parse(&trades, self.symbol_filter.clone(), self.year);
-                    self.option_tax_info = Some(option_tax_info);
+                self.recompute();
+                true
+            }
+            Msg::UpdateLongTermDays(days) => {
+                if let Ok(days) = days.trim().parse() {
+                    self.long_term_days = days;
+                    self.recompute();
                 }
                 true
             }
+            Msg::UpdateState(state) => {
+                self.state = match state.as_str() {
+                    "california" => state::State::California,
+                    "illinois" => state::State::Illinois,
+                    "massachusetts" => state::State::Massachusetts,
+                    "pennsylvania" => state::State::Pennsylvania,
+                    "washington" => state::State::Washington,
+                    _ => state::State::NoStateTax,
+                };
+                self.recompute();
+                true
+            }
+            Msg::UpdateOpeningLots(csv) => {
+                self.update_opening_lots(csv);
+                self.recompute();
+                true
+            }
             Msg::Err(err) => {
                 self.err = err;
                 true
@@ -175,62 +332,34 @@ impl Component for Model {
         // This gives us a component's "`Scope`" which allows us to send messages, etc to the component.
         //let link = ctx.link();
 
-        let mut earnings = 0.;
+        let selected_year = self.year.map(|year| tax::exchange_date(year.start).year());
+        let mut earnings = Decimal::ZERO;
         let mut short_term_trades = 0;
         if let Some(info) = &self.stock_tax_info {
-            earnings += info
-                .iter()
-                .map(|(_s, data)| {
-                    let mut sum = 0.;
-                    for d in data {
-                        match d {
-                            tax::Information::PriceDiff(a, _d) => sum += a,
-                            tax::Information::TimeDiff(d) => {
-                                if d.num_days() < 365 {
-                                    short_term_trades += 1;
-                                }
-                            }
-                            tax::Information::Fees(f) => sum -= f,
-                            _ => (),
-                        };
-                    }
-                    sum
-                })
-                .sum::<f64>();
+            earnings += info.values().map(tax::Position::gain).sum::<Decimal>();
+            short_term_trades += tax::realized(info)
+                .filter(|lot| lot.term == tax::Term::Short)
+                .count();
         }
 
         if let Some(info) = &self.crypto_tax_info {
-            earnings += info
-                .iter()
-                .map(|(_s, data)| {
-                    let mut sum = 0.;
-                    for d in data {
-                        match d {
-                            tax::Information::PriceDiff(a, _d) => sum += a,
-                            tax::Information::Fees(f) => sum -= f,
-                            _ => (),
-                        };
-                    }
-                    sum
-                })
-                .sum::<f64>();
+            earnings += info.values().map(tax::Position::gain).sum::<Decimal>();
         }
         if let Some(info) = &self.option_tax_info {
-            for (_, data) in info {
+            for data in info.values() {
                 for i in data {
-                    match i {
-                        option_tax::Information::TotalDiff(a) => earnings += a,
-                        _ => {}
+                    if let option_tax::Information::TotalDiff(a) = i {
+                        earnings += a
                     }
                 }
             }
         }
-        let information = if earnings != 0. {
+        let information = if !earnings.is_zero() {
             html! {
                 <>
                 <div class="dark:text-white">
                     {
-                        format!("Total capital earnings: ${}", (earnings * 100.).round()/100.)
+                        format!("Total capital earnings: ${}", self.rounding.round(earnings))
                     }
                 </div>
                <div class="dark:text-white">
@@ -256,6 +385,30 @@ impl Component for Model {
         } else {
             html! {}
         };
+        // the years, estimates and forms are shown even when the gains add up to nothing, as a loss
+        // carried in can still be deducted
+        let forms = html! {
+            <>
+            if !self.form_6781.gain.is_zero() {
+                <div class="dark:text-white">
+                    {
+                        format!(
+                            "Form 6781: ${} net, ${} short-term (40%), ${} long-term (60%)",
+                            self.form_6781.gain, self.form_6781.short_term, self.form_6781.long_term
+                        )
+                    }
+                </div>
+            }
+            { self.view_years() }
+            { self.view_estimate() }
+            if let Some((csv_url, html_url)) = &self.report_urls {
+                <div class="dark:text-white">
+                    <a class="underline mr-4" href={csv_url.to_string()} download="form-8949.csv">{"Download Form 8949 (CSV)"}</a>
+                    <a class="underline" href={html_url.to_string()} target="_blank">{"Printable Form 8949 and Schedule D"}</a>
+                </div>
+            }
+            </>
+        };
 
         html! {
             <>
@@ -264,96 +417,13 @@ impl Component for Model {
             <div class="text-center">
             <h1 class="text-3xl font-medium leading-tight mt-0 mb-2 text-blue-600 dark:text-white">{"client-sided stock tax analyzer"}</h1>
             <div class="inline-flex flex-wrap mt-8">
-                <div class="max-w-2xl rounded-lg  bg-white dark:bg-gray-900">
-                    <div class="m-4">
-                        <label class="inline-block mb-2 text-gray-500 dark:text-gray-100">{"Upload Stock History"}</label>
-                            <div class="flex items-center justify-center w-full">
-                                <label
-                                    class="flex flex-col w-full h-32 border-4 border-blue-200 dark:border-blue-800 border-dashed hover:bg-gray-100 hover:border-gray-300 dark:hover:bg-gray-800">
-                                    <div class="flex flex-col items-center justify-center pt-7">
-                                        <svg xmlns="http://www.w3.org/2000/svg" class="w-8 h-8 text-gray-400 dark:text-gray-100 group-hover:text-gray-600"
-                                            fill="none" viewBox="0 0 24 24" stroke="currentColor">
-                                            <path stroke-linecap="round" stroke-linejoin="round" stroke-width="2"
-                                            d="M7 16a4 4 0 01-.88-7.903A5 5 0 1115.9 6L16 6a5 5 0 011 9.9M15 13l-3-3m0 0l-3 3m3-3v12" />
-                                        </svg>
-                                        <p class="pt-1 text-sm tracking-wider text-gray-400 dark:text-gray-100 group-hover:text-gray-600">
-                                                {"Attach a file"}</p>
-                                    </div>
-                                    <input type="file" class="opacity-0" onchange={ctx.link().callback(move |e: Event| {
-                                        let input: HtmlInputElement = e.target_unchecked_into();
-                                        if let Some(files) = input.files() {
-                                            let file = files.get(0).unwrap();
-                                            let result = File::from(web_sys::File::from(file));
-                                            Msg::File(result, Investment::Stock)
-                                        }
-                                        else {
-                                            Msg::Err("Something went wrong with upload".to_string())
-                                        }
-                                    })}/>
-                                </label>
-                            </div>
-                        </div>
-                    </div>
-                <div class="max-w-2xl rounded-lg  bg-white dark:bg-gray-900">
-                    <div class="m-4">
-                        <label class="inline-block mb-2 text-gray-500 dark:text-gray-100">{"Upload Crypto History"}</label>
-                            <div class="flex items-center justify-center w-full">
-                                <label
-                                    class="flex flex-col w-full h-32 border-4 border-blue-200 dark:border-blue-800 border-dashed hover:bg-gray-100 hover:border-gray-300 dark:hover:bg-gray-800">
-                                    <div class="flex flex-col items-center justify-center pt-7">
-                                        <svg xmlns="http://www.w3.org/2000/svg" class="w-8 h-8 text-gray-400 dark:text-gray-100 group-hover:text-gray-600"
-                                            fill="none" viewBox="0 0 24 24" stroke="currentColor">
-                                            <path stroke-linecap="round" stroke-linejoin="round" stroke-width="2"
-                                            d="M7 16a4 4 0 01-.88-7.903A5 5 0 1115.9 6L16 6a5 5 0 011 9.9M15 13l-3-3m0 0l-3 3m3-3v12" />
-                                        </svg>
-                                        <p class="pt-1 text-sm tracking-wider text-gray-400 dark:text-gray-100 group-hover:text-gray-600">
-                                                {"Attach a file"}</p>
-                                    </div>
-                                    <input type="file" class="opacity-0" onchange={ctx.link().callback(move |e: Event| {
-                                        let input: HtmlInputElement = e.target_unchecked_into();
-                                        if let Some(files) = input.files() {
-                                            let file = files.get(0).unwrap();
-                                            let result = File::from(web_sys::File::from(file));
-                                            Msg::File(result, Investment::Crypto)
-                                        }
-                                        else {
-                                            Msg::Err("Something went wrong with upload".to_string())
-                                        }
-                                    })}/>
-                                </label>
-                            </div>
-                        </div>
-                    </div>
-                    <div class="max-w-2xl rounded-lg  bg-white dark:bg-gray-900">
-                    <div class="m-4">
-                        <label class="inline-block mb-2 text-gray-500 dark:text-gray-100">{"Upload Option History"}</label>
-                            <div class="flex items-center justify-center w-full">
-                                <label
-                                    class="flex flex-col w-full h-32 border-4 border-blue-200 dark:border-blue-800 border-dashed hover:bg-gray-100 hover:border-gray-300 dark:hover:bg-gray-800">
-                                    <div class="flex flex-col items-center justify-center pt-7">
-                                        <svg xmlns="http://www.w3.org/2000/svg" class="w-8 h-8 text-gray-400 dark:text-gray-100 group-hover:text-gray-600"
-                                            fill="none" viewBox="0 0 24 24" stroke="currentColor">
-                                            <path stroke-linecap="round" stroke-linejoin="round" stroke-width="2"
-                                            d="M7 16a4 4 0 01-.88-7.903A5 5 0 1115.9 6L16 6a5 5 0 011 9.9M15 13l-3-3m0 0l-3 3m3-3v12" />
-                                        </svg>
-                                        <p class="pt-1 text-sm tracking-wider text-gray-400 dark:text-gray-100 group-hover:text-gray-600">
-                                                {"Attach a file"}</p>
-                                    </div>
-                                    <input type="file" class="opacity-0" onchange={ctx.link().callback(move |e: Event| {
-                                        let input: HtmlInputElement = e.target_unchecked_into();
-                                        if let Some(files) = input.files() {
-                                            let file = files.get(0).unwrap();
-                                            let result = File::from(web_sys::File::from(file));
-                                            Msg::File(result, Investment::Options)
-                                        }
-                                        else {
-                                            Msg::Err("Something went wrong with upload".to_string())
-                                        }
-                                    })}/>
-                                </label>
-                            </div>
-                        </div>
-                    </div>
+                { Self::view_upload(ctx, "Upload Stock History", Upload::Stock) }
+                { Self::view_upload(ctx, "Upload Crypto History", Upload::Crypto) }
+                { Self::view_upload(ctx, "Upload Option History", Upload::Options) }
+                { Self::view_upload(ctx, "Upload Corporate Actions", Upload::CorporateActions) }
+                { Self::view_upload(ctx, "Upload Year-End Option Marks", Upload::OptionMarks) }
+                { Self::view_upload(ctx, "Upload Transferred-In Lots", Upload::OpeningLots) }
+                { Self::view_upload(ctx, "Upload Prices (CSV or JSON)", Upload::Prices) }
 
                 </div>
                 <div class="m-4">
@@ -371,33 +441,137 @@ impl Component for Model {
                         Msg::UpdateYearFilter(input.value())
 
                     })}>
-                        <option selected=true value="none">{"None"}</option>
-                        <option value="2019">{"2019"}</option>
-                        <option value="2020">{"2020"}</option>
-                        <option value="2021">{"2021"}</option>
-                        <option value="2022">{"2022"}</option>
+                        <option selected={self.year.is_none()} value="none">{"None"}</option>
+                        { for self.tax_years().into_iter().map(|year| html! {
+                            <option selected={selected_year == Some(year)} value={year.to_string()}>{ year }</option>
+                        }) }
+                    </select>
+                </div>
+                <div class="m-4">
+                    <p class="mb-2 text-gray-500 dark:text-gray-100">{ "Lot matching" }</p>
+                    <select onchange={ctx.link().callback(move |e: Event| {
+                        let input: HtmlInputElement = e.target_unchecked_into();
+                        Msg::UpdateLotMethod(input.value())
+
+                    })}>
+                        <option selected=true value="fifo">{"First in, first out"}</option>
+                        <option value="lifo">{"Last in, first out"}</option>
+                        <option value="hifo">{"Highest cost first"}</option>
+                        <option value="lofo">{"Lowest cost first"}</option>
+                        <option value="specific">{"Specific identification"}</option>
+                    </select>
+                </div>
+                <div class="m-4">
+                    <label class="text-gray-500 dark:text-gray-100">
+                        <input class="mr-2" type="checkbox" checked={self.short_sales} onchange={ctx.link().callback(move |e: Event| {
+                            let input: HtmlInputElement = e.target_unchecked_into();
+                            Msg::UpdateShortSales(input.checked())
+                        })}/>
+                        { "Sells of shares not held are short sales" }
+                    </label>
+                </div>
+                <div class="m-4">
+                    <p class="mb-2 text-gray-500 dark:text-gray-100">{ "Filing status" }</p>
+                    <select onchange={ctx.link().callback(move |e: Event| {
+                        let input: HtmlInputElement = e.target_unchecked_into();
+                        Msg::UpdateFilingStatus(input.value())
+
+                    })}>
+                        <option selected=true value="single">{"Single"}</option>
+                        <option value="joint">{"Married filing jointly"}</option>
+                        <option value="separate">{"Married filing separately"}</option>
+                        <option value="head">{"Head of household"}</option>
+                    </select>
+                </div>
+                <div class="m-4">
+                    <p class="mb-2 text-gray-500 dark:text-gray-100">{ "State" }</p>
+                    <select onchange={ctx.link().callback(move |e: Event| {
+                        let input: HtmlInputElement = e.target_unchecked_into();
+                        Msg::UpdateState(input.value())
+
+                    })}>
+                        <option selected=true value="none">{"No state tax"}</option>
+                        <option value="california">{"California"}</option>
+                        <option value="illinois">{"Illinois"}</option>
+                        <option value="massachusetts">{"Massachusetts"}</option>
+                        <option value="pennsylvania">{"Pennsylvania"}</option>
+                        <option value="washington">{"Washington"}</option>
+                    </select>
+                </div>
+                <div class="m-4">
+                    <p class="mb-2 text-gray-500 dark:text-gray-100">{ "Other ordinary income" }</p>
+                    <input class="bg-gray-40 dark:bg-gray-800 dark:text-gray-100 border-2 border-blue-200 dark:border-blue-800 p-2" placeholder="85000" type="text" oninput={ctx.link().callback(move |e: InputEvent| {
+                            let input: HtmlInputElement = e.target_unchecked_into();
+                            Msg::UpdateOrdinaryIncome(input.value())
+                        }
+                    )}/>
+                </div>
+                <div class="m-4">
+                    <p class="mb-2 text-gray-500 dark:text-gray-100">{ "Days ahead to look for lots turning long-term" }</p>
+                    <input class="bg-gray-40 dark:bg-gray-800 dark:text-gray-100 border-2 border-blue-200 dark:border-blue-800 p-2" type="number" min="0" value={self.long_term_days.to_string()} oninput={ctx.link().callback(move |e: InputEvent| {
+                            let input: HtmlInputElement = e.target_unchecked_into();
+                            Msg::UpdateLongTermDays(input.value())
+                        }
+                    )}/>
+                </div>
+                <div class="m-4">
+                    <p class="mb-2 text-gray-500 dark:text-gray-100">{ "Round form amounts to" }</p>
+                    <select onchange={ctx.link().callback(move |e: Event| {
+                        let input: HtmlInputElement = e.target_unchecked_into();
+                        Msg::UpdateRounding(input.value())
+
+                    })}>
+                        <option selected=true value="cents">{"Cents"}</option>
+                        <option value="dollars">{"Whole dollars"}</option>
                     </select>
                 </div>
+                <div class="m-4">
+                    <p class="mb-2 text-gray-500 dark:text-gray-100">{ "Section 1256 underlyings" }</p>
+                    <input class="bg-gray-40 dark:bg-gray-800 dark:text-gray-100 border-2 border-blue-200 dark:border-blue-800 p-2" placeholder="SPX, NDX" type="text" value={self.section_1256.clone()} oninput={ctx.link().callback(move |e: InputEvent| {
+                            let input: HtmlInputElement = e.target_unchecked_into();
+                            Msg::UpdateSection1256(input.value())
+                        }
+                    )}/>
+                </div>
+                <div class="m-4">
+                    <p class="mb-2 text-gray-500 dark:text-gray-100">{ "Shares transferred in (symbol, quantity, date acquired, cost basis)" }</p>
+                    <textarea class="bg-gray-40 dark:bg-gray-800 dark:text-gray-100 border-2 border-blue-200 dark:border-blue-800 p-2 font-mono" rows="4" cols="48" value={self.opening_lots_csv.clone()} oninput={ctx.link().callback(move |e: InputEvent| {
+                            let input: HtmlTextAreaElement = e.target_unchecked_into();
+                            Msg::UpdateOpeningLots(input.value())
+                        }
+                    )}/>
+                    <p class="text-red-600 dark:text-red-400">{ &self.opening_lots_err }</p>
+                </div>
+                if self.lot_method == tax::LotMethod::Specific {
+                    <div class="inline-flex flex-wrap">
+                        { Self::view_upload(ctx, "Upload Lot Selections", Upload::LotSelections) }
+                    </div>
+                }
                 </div>
 
                 <div class="w-4/5 mx-auto">
                     { information }
+                    { forms }
+                    { self.view_data_problems() }
+                    { self.view_recommendations() }
+                    { self.view_upcoming() }
+                    { self.view_unrealized() }
                     if let Some(info) = &self.stock_tax_info {
                         <>
                             <h2 class="text-black dark:text-gray-200 text-3xl font-medium leading-tight" id="stocks">{"Stocks"}</h2>
-                            <div class="flex items-start flex-wrap gap-4 justify-center">{ for info.iter().map(|f| Self::view_tax(f)) }</div>
+                            <div class="flex items-start flex-wrap gap-4 justify-center">{ for info.iter().map(|data| self.view_tax(data)) }</div>
                         </>
                     }
                     if let Some(info) = &self.crypto_tax_info {
                         <>
                             <h2 class="text-black dark:text-gray-200 text-3xl font-medium leading-tight" id="crypto">{"Cryptocurrency"}</h2>
-                            <div class="flex items-start flex-wrap gap-4 justify-center">{ for info.iter().map(|f| Self::view_tax(f)) }</div>
+                            <div class="flex items-start flex-wrap gap-4 justify-center">{ for info.iter().map(|data| self.view_tax(data)) }</div>
                         </>
                     }
                     if let Some(info) = &self.option_tax_info {
                         <>
                             <h2 class="text-black dark:text-gray-200 text-3xl font-medium leading-tight" id="options">{"Options"}</h2>
-                            <div class="flex items-start flex-wrap gap-4 justify-center">{ for info.iter().map(|f| Self::view_option_tax(f)) }</div>
+                            <div class="flex items-start flex-wrap gap-4 justify-center">{ for info.iter().map(|data| self.view_option_tax(data)) }</div>
                         </>
                     }
                 </div>
@@ -421,20 +595,583 @@ impl Component for Model {
 }
 
 impl Model {
-    fn view_tax(data: (&String, &Vec<tax::Information>)) -> Html {
-        let (symbol, information) = data;
+    // The years to filter by: every year with federal tables and every year traded in
+    fn tax_years(&self) -> Vec<i32> {
+        let mut years: Vec<i32> = federal::years().collect();
+        for trades in [&self.stock_csv_data, &self.crypto_csv_data]
+            .iter()
+            .copied()
+            .flatten()
+        {
+            years.extend(
+                trades
+                    .values()
+                    .flatten()
+                    .map(|trade| tax::exchange_date(trade.date).year()),
+            );
+        }
+        if let Some(trades) = &self.option_csv_data {
+            years.extend(
+                trades
+                    .values()
+                    .flatten()
+                    .map(|trade| tax::exchange_date(trade.order_created_at).year()),
+            );
+        }
+        years.sort_unstable();
+        years.dedup();
+        years
+    }
 
-        let mut sum = 0.;
-        for info in information {
-            match info {
-                tax::Information::PriceDiff(a, _d) => sum += a,
-                _ => (),
+    // Keeps the typed or uploaded CSV as it is, and the lots from it when it parses
+    fn update_opening_lots(&mut self, csv: String) {
+        match csv_parser::parse_opening_lots(&csv) {
+            Ok(lots) => {
+                self.opening_lots = lots;
+                self.opening_lots_err = String::new();
+            }
+            Err(csv_err) => {
+                self.opening_lots_err = format!("{:?}", csv_err);
+            }
+        }
+        self.opening_lots_csv = csv;
+    }
+
+    // Every engine over the loaded histories for one symbol filter and year
+    fn run_engines(&self, symbol_filter: String, year: Option<tax::TaxYear>) -> Engines {
+        let mut engines = Engines::default();
+        // options go first, as the ones exercised or assigned change the stock trades
+        let mut exercises = Vec::new();
+        let section_1256: Vec<String> = self
+            .section_1256
+            .split(',')
+            .map(|symbol| symbol.trim().to_uppercase())
+            .filter(|symbol| !symbol.is_empty())
+            .collect();
+        if let Some(trades) = &self.option_csv_data {
+            let (option_tax_info, option_exercises) = option_tax::parse(
+                trades,
+                symbol_filter.clone(),
+                year,
+                chrono::Utc::now(),
+                self.stock_csv_data
+                    .as_ref()
+                    .unwrap_or(&csv_parser::Trades::new()),
+                &section_1256,
+                &self.option_marks,
+            );
+            engines.options = Some(option_tax_info);
+            exercises = option_exercises;
+        }
+        if let Some(trades) = &self.stock_csv_data {
+            let stock_tax_info = tax::parse(
+                &option_tax::fold_exercises(trades, &exercises),
+                symbol_filter.clone(),
+                year,
+                self.lot_method,
+                self.short_sales,
+                &self.lot_selections,
+                &self.corporate_actions,
+                &self.opening_lots,
+            );
+            engines.stocks = Some(stock_tax_info);
+        }
+        if let Some(trades) = &self.crypto_csv_data {
+            let crypto_tax_info = tax::parse(
+                trades,
+                symbol_filter,
+                year,
+                self.lot_method,
+                // crypto is not sold short on an exchange
+                false,
+                &self.lot_selections,
+                &self.corporate_actions,
+                // the transferred-in lots are for the stock history
+                &csv_parser::OpeningLots::new(),
+            );
+            engines.crypto = Some(crypto_tax_info);
+        }
+
+        engines
+    }
+
+    // Re-run every engine over the loaded histories with the current filters and settings
+    fn recompute(&mut self) {
+        let engines = self.run_engines(self.symbol_filter.clone(), self.year);
+        self.option_tax_info = engines.options;
+        self.stock_tax_info = engines.stocks;
+        self.crypto_tax_info = engines.crypto;
+
+        // every year of every symbol, as a loss carries over from one year to the next
+        let all = self.run_engines(String::new(), None);
+        let lots = all
+            .stocks
+            .iter()
+            .chain(all.crypto.iter())
+            .flat_map(tax::realized)
+            .chain(all.options.iter().flat_map(option_tax::realized));
+        self.years = capital_gains::summarize(
+            lots,
+            all.options.iter().flat_map(option_tax::section_1256),
+            self.filing_status,
+            self.year.map(|year| tax::exchange_date(year.start).year()),
+        );
+
+        // the lots held now, whatever year is filtered on
+        let now = chrono::Utc::now();
+        self.recommendations = Vec::new();
+        self.upcoming = Vec::new();
+        self.unrealized = Vec::new();
+        let histories = [
+            (&all.stocks, &self.stock_csv_data),
+            (&all.crypto, &self.crypto_csv_data),
+        ];
+        for (info, trades) in histories {
+            if let (Some(info), Some(trades)) = (info, trades) {
+                self.recommendations.append(&mut harvest::recommendations(
+                    info,
+                    trades,
+                    &self.prices,
+                    now,
+                ));
+                self.upcoming.append(&mut harvest::upcoming_long_term(
+                    info,
+                    &self.prices,
+                    now,
+                    self.long_term_days,
+                ));
+                self.unrealized
+                    .append(&mut unrealized::unrealized(info, &self.prices, now));
+            }
+        }
+        self.upcoming
+            .sort_by_key(|upcoming| upcoming.long_term_from);
+        self.recommendations
+            .sort_by_key(|recommendation| std::cmp::Reverse(recommendation.loss));
+        self.unrealized.sort_by(|a, b| a.symbol.cmp(&b.symbol));
+
+        let summary = match self.year {
+            Some(year) => {
+                let year = tax::exchange_date(year.start).year();
+                self.years
+                    .iter()
+                    .find(|summary| summary.year == year)
+                    .cloned()
+                    .unwrap_or(capital_gains::YearSummary {
+                        year,
+                        ..Default::default()
+                    })
+            }
+            None => self.years.last().cloned().unwrap_or_default(),
+        };
+        self.estimate = None;
+        self.state_estimate = None;
+        self.estimate_year = None;
+        if !self.years.is_empty() {
+            self.estimate_year = Some(summary.year);
+            match federal::estimate(&summary, self.filing_status, self.ordinary_income) {
+                Ok(estimate) => self.estimate = estimate,
+                Err(csv_err) => self.err = format!("{:?}", csv_err),
+            }
+            match state::estimate(
+                self.state,
+                &summary,
+                self.filing_status,
+                self.ordinary_income,
+            ) {
+                Ok(estimate) => self.state_estimate = estimate,
+                Err(csv_err) => self.err = format!("{:?}", csv_err),
+            }
+        }
+
+        let mut rows = Vec::new();
+        if let Some(info) = &self.stock_tax_info {
+            rows.append(&mut report::form_8949(
+                tax::realized(info),
+                report::Reporting::Covered,
+                self.rounding,
+            ));
+        }
+        if let Some(info) = &self.crypto_tax_info {
+            rows.append(&mut report::form_8949(
+                tax::realized(info),
+                report::Reporting::Unreported,
+                self.rounding,
+            ));
+        }
+        if let Some(info) = &self.option_tax_info {
+            rows.append(&mut report::form_8949(
+                option_tax::realized(info),
+                report::Reporting::Covered,
+                self.rounding,
+            ));
+        }
+        self.form_6781 = match &self.option_tax_info {
+            Some(info) => report::form_6781(option_tax::section_1256(info), self.rounding),
+            None => report::Form6781::default(),
+        };
+        rows.sort_by_key(|row| (row.form_box, row.sold));
+        self.report_urls = match report::to_csv(&rows) {
+            Ok(csv) if !rows.is_empty() || !self.form_6781.gain.is_zero() => {
+                let lines = report::schedule_d(&rows, &self.form_6781);
+                let html = report::to_html(&rows, &self.form_6781, &lines);
+                Some((
+                    ObjectUrl::from(Blob::new_with_options(csv.as_str(), Some("text/csv"))),
+                    ObjectUrl::from(Blob::new_with_options(html.as_str(), Some("text/html"))),
+                ))
+            }
+            Ok(_) => None,
+            Err(csv_err) => {
+                self.err = format!("{:?}", csv_err);
+                None
+            }
+        };
+    }
+
+    // Short- and long-term totals of every year, with the loss taken against ordinary income and
+    // what is carried over
+    fn view_years(&self) -> Html {
+        if self.years.is_empty() {
+            return html! {};
+        }
+        let cell = |amount: Decimal| html! { <td class="px-2 text-right">{ format!("${}", self.rounding.round(amount)) }</td> };
+        html! {
+            <table class="dark:text-white mx-auto my-2">
+                <tr>
+                    <th class="px-2">{"Year"}</th>
+                    <th class="px-2">{"Short-term"}</th>
+                    <th class="px-2">{"Long-term"}</th>
+                    <th class="px-2">{"Carried in (short/long)"}</th>
+                    <th class="px-2">{"Net short-term"}</th>
+                    <th class="px-2">{"Net long-term"}</th>
+                    <th class="px-2">{"Net"}</th>
+                    <th class="px-2">{"Against ordinary income"}</th>
+                    <th class="px-2">{"Carried out (short/long)"}</th>
+                </tr>
+                { for self.years.iter().map(|year| html! {
+                    <tr>
+                        <td class="px-2">{ year.year }</td>
+                        { cell(year.short_term) }
+                        { cell(year.long_term) }
+                        <td class="px-2 text-right">{ format!("${} / ${}", self.rounding.round(year.short_term_carryover_in), self.rounding.round(year.long_term_carryover_in)) }</td>
+                        { cell(year.net_short_term) }
+                        { cell(year.net_long_term) }
+                        { cell(year.net()) }
+                        { cell(-year.deduction) }
+                        <td class="px-2 text-right">{ format!("${} / ${}", self.rounding.round(year.short_term_carryover_out), self.rounding.round(year.long_term_carryover_out)) }</td>
+                    </tr>
+                }) }
+            </table>
+        }
+    }
+
+    fn view_estimate(&self) -> Html {
+        let year = match self.estimate_year {
+            Some(year) => year,
+            None => return html! {},
+        };
+        let estimate = match &self.estimate {
+            Some(estimate) => estimate,
+            None => {
+                return html! {
+                    <div class="dark:text-white">{ format!("No federal tax table for {}", year) }</div>
+                }
+            }
+        };
+        html! {
+            <div class="dark:text-white">
+                <div>
+                    { format!(
+                        "Estimated federal tax ({} tables): ${}, of which ${} from trading",
+                        estimate.table_year,
+                        self.rounding.round(estimate.total()),
+                        self.rounding.round(estimate.on_gains)
+                    ) }
+                </div>
+                <div>
+                    { format!(
+                        "Ordinary ${} + long-term ${} + net investment income ${} on ${} taxable",
+                        self.rounding.round(estimate.ordinary_tax),
+                        self.rounding.round(estimate.long_term_tax),
+                        self.rounding.round(estimate.niit),
+                        self.rounding.round(estimate.taxable_income)
+                    ) }
+                </div>
+                <div>
+                    { format!(
+                        "Marginal rate {}% short-term, {}% long-term. Effective rate {}%",
+                        estimate.marginal_short_term.round_dp(1),
+                        estimate.marginal_long_term.round_dp(1),
+                        estimate.effective_rate().round_dp(1)
+                    ) }
+                </div>
+                if let Some(state) = &self.state_estimate {
+                    <div>
+                        { format!(
+                            "{} tax ({} tables): ${}, of which ${} from trading. Federal and state: ${}",
+                            state.state,
+                            state.table_year,
+                            self.rounding.round(state.tax),
+                            self.rounding.round(state.on_gains),
+                            self.rounding.round(estimate.total() + state.tax)
+                        ) }
+                    </div>
+                } else if self.state != state::State::NoStateTax {
+                    <div>{ format!("No {} tax table for {}", self.state, year) }</div>
+                }
+            </div>
+        }
+    }
+
+    // Lots that could be sold at a loss to offset gains
+    fn view_recommendations(&self) -> Html {
+        if self.recommendations.is_empty() {
+            return html! {};
+        }
+        html! {
+            <div class="bg-gray-100 dark:bg-gray-800 dark:text-white text-left rounded-md p-2 my-4">
+                <h2 class="text-xl font-medium">{"Tax-loss harvesting"}</h2>
+                { for self.recommendations.iter().map(|recommendation| html! {
+                    <div class="my-1">
+                        { format!(
+                            "{}: {} bought {} for ${}, worth ${} at ${}. Selling realizes a ${} {} loss",
+                            recommendation.symbol,
+                            recommendation.lot.quantity,
+                            recommendation.lot.bought.format("%Y-%m-%d"),
+                            self.rounding.round(recommendation.lot.cost_basis),
+                            self.rounding.round(recommendation.price * recommendation.lot.quantity),
+                            recommendation.price,
+                            self.rounding.round(recommendation.loss),
+                            match recommendation.term { tax::Term::Short => "short-term", tax::Term::Long => "long-term" }
+                        ) }
+                        if let Some(date) = recommendation.wash_buy {
+                            <div class="bg-yellow-200 dark:bg-yellow-600 p-1">
+                                { format!("Bought again on {}, so selling now would be a wash sale", date.format("%Y-%m-%d")) }
+                            </div>
+                        }
+                    </div>
+                }) }
+                <div class="text-gray-500 dark:text-gray-300">{"A buy of the same symbol in the 30 days after selling washes the loss too"}</div>
+            </div>
+        }
+    }
+
+    // What the held lots would realize at the uploaded prices, next to what was realized
+    fn view_unrealized(&self) -> Html {
+        if self.unrealized.is_empty() {
+            return html! {};
+        }
+        let term = |term| match term {
+            tax::Term::Short => "short-term",
+            tax::Term::Long => "long-term",
+        };
+        let short_term: Decimal = self
+            .unrealized
+            .iter()
+            .map(|symbol| symbol.gain(tax::Term::Short))
+            .sum();
+        let long_term: Decimal = self
+            .unrealized
+            .iter()
+            .map(|symbol| symbol.gain(tax::Term::Long))
+            .sum();
+        let realized: Decimal = self
+            .years
+            .iter()
+            .map(|year| year.short_term + year.long_term)
+            .sum();
+        html! {
+            <div class="bg-gray-100 dark:bg-gray-800 dark:text-white text-left rounded-md p-2 my-4">
+                <h2 class="text-xl font-medium">{"Unrealized gains"}</h2>
+                <div>
+                    { format!(
+                        "${} short-term and ${} long-term if sold now, next to ${} realized over all years",
+                        self.rounding.round(short_term),
+                        self.rounding.round(long_term),
+                        self.rounding.round(realized)
+                    ) }
+                </div>
+                { for self.unrealized.iter().map(|symbol| html! {
+                    <details class="my-1">
+                        <summary>
+                            { format!(
+                                "{} at ${} on {}: ${} short-term, ${} long-term",
+                                symbol.symbol,
+                                symbol.price,
+                                symbol.as_of.format("%Y-%m-%d"),
+                                self.rounding.round(symbol.gain(tax::Term::Short)),
+                                self.rounding.round(symbol.gain(tax::Term::Long))
+                            ) }
+                        </summary>
+                        { for symbol.lots.iter().map(|lot| html! {
+                            <div class="ml-8">
+                                { format!(
+                                    "{} {} {}: ${} {}",
+                                    lot.quantity,
+                                    if lot.short { "sold short" } else { "bought" },
+                                    lot.acquired.format("%Y-%m-%d"),
+                                    self.rounding.round(lot.gain),
+                                    term(lot.term)
+                                ) }
+                            </div>
+                        }) }
+                    </details>
+                }) }
+            </div>
+        }
+    }
+
+    // Lots that would be taxed at the long-term rates if held a little longer
+    fn view_upcoming(&self) -> Html {
+        if self.upcoming.is_empty() {
+            return html! {};
+        }
+        html! {
+            <div class="bg-gray-100 dark:bg-gray-800 dark:text-white text-left rounded-md p-2 my-4">
+                <h2 class="text-xl font-medium">{ format!("Turning long-term in the next {} days", self.long_term_days) }</h2>
+                { for self.upcoming.iter().map(|upcoming| html! {
+                    <div class="my-1">
+                        { format!(
+                            "{}: {} bought {} is long-term from {}",
+                            upcoming.symbol,
+                            upcoming.lot.quantity,
+                            upcoming.lot.bought.format("%Y-%m-%d"),
+                            upcoming.long_term_from.format("%Y-%m-%d")
+                        ) }
+                        { match upcoming.gain {
+                            Some(gain) => format!(", ${} unrealized", self.rounding.round(gain)),
+                            None => ", no price uploaded".to_string(),
+                        } }
+                    </div>
+                }) }
+            </div>
+        }
+    }
+
+    // Sells and closes the histories have nothing open for, which make the totals wrong until
+    // fixed
+    fn view_data_problems(&self) -> Html {
+        // where each problem is: the file, and its line when it is about one
+        let mut problems: Vec<(&str, Option<u64>, String)> = Vec::new();
+        let histories = [
+            ("Stock history", &self.stock_tax_info),
+            ("Crypto history", &self.crypto_tax_info),
+        ];
+        for (history, info) in histories {
+            if let Some(info) = info {
+                problems.extend(tax::unmatched_sells(info).map(|(symbol, sell)| {
+                    let problem = format!(
+                        "{} sold {} at ${} on {} with {} held, {} {}",
+                        symbol,
+                        sell.quantity,
+                        sell.price,
+                        sell.date.format("%Y-%m-%d"),
+                        sell.held,
+                        sell.unmatched,
+                        if sell.short {
+                            "opened as a short sale"
+                        } else {
+                            "not matched to any buy"
+                        }
+                    );
+                    (history, Some(sell.row), problem)
+                }));
             }
         }
+        if let Some(info) = &self.option_tax_info {
+            problems.extend(option_tax::unmatched_closes(info).map(|close| {
+                let problem = format!(
+                    "{} closed {} at ${} on {}, {} not matched to any open",
+                    close.contract,
+                    close.quantity,
+                    close.price,
+                    close.date.format("%Y-%m-%d"),
+                    close.unmatched
+                );
+                ("Option history", Some(close.row), problem)
+            }));
+            problems.extend(option_tax::missing_marks(info).map(|missing| {
+                let problem = format!(
+                    "{} {} held at the end of {} with no mark to market, so Form 6781 leaves out its gain for the year",
+                    missing.quantity.normalize(),
+                    missing.contract,
+                    missing.year
+                );
+                ("Year-end option marks", None, problem)
+            }));
+        }
+        if let Some(info) = &self.stock_tax_info {
+            problems.extend(tax::lost_transfers(info).map(|(symbol, transfer)| {
+                let problem = format!(
+                    "{} shares of {} moved to {} on {} were left out, as the renames between them go in a circle",
+                    transfer.quantity.normalize(),
+                    transfer.from,
+                    symbol,
+                    transfer.date.format("%Y-%m-%d")
+                );
+                ("Corporate actions", Some(transfer.row), problem)
+            }));
+        }
+        if problems.is_empty() {
+            return html! {};
+        }
+        problems.sort_by(|a, b| (a.0, a.1).cmp(&(b.0, b.1)));
+        html! {
+            <div class="bg-red-100 dark:bg-red-900 dark:text-white text-left rounded-md p-2 my-4">
+                <h2 class="text-xl font-medium">{"Data problems"}</h2>
+                { for problems.into_iter().map(|(file, row, problem)| html! {
+                    <div> { match row {
+                        Some(row) => format!("{} line {}: {}", file, row, problem),
+                        None => format!("{}: {}", file, problem),
+                    } } </div>
+                }) }
+            </div>
+        }
+    }
 
-        let color_class = if sum > 0. {
+    fn view_upload(ctx: &Context<Self>, label: &str, upload: Upload) -> Html {
+        html! {
+                <div class="max-w-2xl rounded-lg  bg-white dark:bg-gray-900">
+                    <div class="m-4">
+                        <label class="inline-block mb-2 text-gray-500 dark:text-gray-100">{label}</label>
+                            <div class="flex items-center justify-center w-full">
+                                <label
+                                    class="flex flex-col w-full h-32 border-4 border-blue-200 dark:border-blue-800 border-dashed hover:bg-gray-100 hover:border-gray-300 dark:hover:bg-gray-800">
+                                    <div class="flex flex-col items-center justify-center pt-7">
+                                        <svg xmlns="http://www.w3.org/2000/svg" class="w-8 h-8 text-gray-400 dark:text-gray-100 group-hover:text-gray-600"
+                                            fill="none" viewBox="0 0 24 24" stroke="currentColor">
+                                            <path stroke-linecap="round" stroke-linejoin="round" stroke-width="2"
+                                            d="M7 16a4 4 0 01-.88-7.903A5 5 0 1115.9 6L16 6a5 5 0 011 9.9M15 13l-3-3m0 0l-3 3m3-3v12" />
+                                        </svg>
+                                        <p class="pt-1 text-sm tracking-wider text-gray-400 dark:text-gray-100 group-hover:text-gray-600">
+                                                {"Attach a file"}</p>
+                                    </div>
+                                    <input type="file" class="opacity-0" onchange={ctx.link().callback(move |e: Event| {
+                                        let input: HtmlInputElement = e.target_unchecked_into();
+                                        if let Some(files) = input.files() {
+                                            let file = files.get(0).unwrap();
+                                            let result = File::from(web_sys::File::from(file));
+                                            Msg::File(result, upload)
+                                        }
+                                        else {
+                                            Msg::Err("Something went wrong with upload".to_string())
+                                        }
+                                    })}/>
+                                </label>
+                            </div>
+                        </div>
+                    </div>
+        }
+    }
+
+    fn view_tax(&self, data: (&String, &tax::Position)) -> Html {
+        let (symbol, position) = data;
+
+        let sum = position.gain();
+
+        let color_class = if sum > Decimal::ZERO {
             "border-green-700"
-        } else if sum == 0.0 {
+        } else if sum.is_zero() {
             "border-gray-700"
         } else {
             "border-red-700"
@@ -445,18 +1182,29 @@ impl Model {
                 <div class="">
                     <h2 class="text-black dark:text-gray-200 text-2xl font-medium leading-tight"> {symbol}</h2>
                     <div class={classes!("bg-gray-200","dark:bg-gray-800", "border-l-8", color_class, "h-96", "overflow-y-auto", "overflow-x-hidden")}>
-                        {for information.iter().map(|f| Self::view_information(f))}
+                        {for position.informations.iter().map(|info| html! {
+                            <>
+                            { self.view_information(info) }
+                            // the lots a sell consumed go right underneath it
+                            if let tax::Information::Sell(_q, _p, date) | tax::Information::Cover(_q, _p, date) | tax::Information::Cash(_q, _p, date) = info {
+                                { for position.realized.iter().filter(|lot| lot.disposed == *date).map(|data| self.view_realized(data)) }
+                            }
+                            </>
+                        })}
+                        { for position.open.iter().map(|data| self.view_open_lot(data)) }
+                        { for position.short.iter().map(|data| self.view_short_lot(data)) }
 
                     </div>
-                    if let Some(tax::Information::Remaing(q)) = information.last() {
-                            <div class="w-fill bg-black text-white"> { format!("Quantity Owned: {}", q) } </div>
+                    <div class="w-fill bg-black text-white"> { format!("Quantity Owned: {}", position.quantity()) } </div>
+                    if !position.short.is_empty() {
+                        <div class="w-fill bg-red-900 text-white"> { format!("Quantity Short: {}", position.short_quantity()) } </div>
                     }
 
                 </div>
         }
     }
 
-    fn view_information(data: &tax::Information) -> Html {
+    fn view_information(&self, data: &tax::Information) -> Html {
         html! {
             <div class="w-96">
             {
@@ -469,46 +1217,110 @@ impl Model {
                     html! {
                         <div class="bg-indigo-400 dark:bg-indigo-800 dark:text-white rounded p-1 mt-2 mr-2 ml-2"> { format!("Sell: {}", q) } </div>
                     },
-                tax::Information::TimeDiff(d) =>
+                tax::Information::SellShort(q, p, _d) =>
                     html! {
-                        <div class="w-80 bg-indigo-200 dark:bg-indigo-600 dark:text-white p-1 ml-8"> { format!("{} days -- {}", d.num_days(), if d.num_days() < 365 { "short-term capital" } else { "long-term capital" }) } </div>
+                        <div class="bg-red-400 dark:bg-red-800 dark:text-white rounded p-1 mt-2 mr-2 ml-2"> { format!("Sell Short: {} at ${}", q, p) } </div>
                     },
-                 tax::Information::PriceDiff(a, _d) =>
+                tax::Information::Cover(q, p, _d) =>
                     html! {
-                        if a > &0. {
-                            <div class="w-64 bg-green-200 dark:bg-green-600 dark:text-white p-1 ml-24"> { format!("${}", (a*100.).round()/100.)} </div>
-                        }
-                        else {
-                            <div class="w-64 bg-red-200 dark:bg-red-600 dark:text-white p-1 ml-24"> { format!("${}", (a*100.).round()/100.)} </div>
-                        }
+                        <div class="bg-red-400 dark:bg-red-800 dark:text-white rounded p-1 mt-2 mr-2 ml-2"> { format!("Buy to Cover: {} at ${}", q, p) } </div>
                     },
-                tax::Information::Fees(f) =>
+                tax::Information::UnmatchedSell(sell) =>
                     html! {
-                        <div class="bg-red-100 dark:bg-red-500 dark:text-white w-64 p-1 ml-24"> { format!("-${} (fees)", f) } </div>
+                        <div class="bg-red-200 dark:bg-red-600 dark:text-white w-80 p-1 ml-8"> { format!("{} of {} sold {} (line {})", sell.unmatched, sell.quantity, if sell.short { "opened as a short sale" } else { "not matched to any buy" }, sell.row) } </div>
+                    },
+                tax::Information::Split(r, d) =>
+                    html! {
+                        <div class="bg-gray-400 dark:bg-gray-600 dark:text-white rounded-md p-1 m-2"> { format!("Split: {} for 1 on {}", r.normalize(), d.format("%Y-%m-%d")) } </div>
+                    },
+                tax::Information::Moved(s, r, d) =>
+                    html! {
+                        <div class="bg-gray-400 dark:bg-gray-600 dark:text-white rounded-md p-1 m-2"> { format!("Became {} {} per share on {}", r.normalize(), s, d.format("%Y-%m-%d")) } </div>
+                    },
+                tax::Information::SpunOff(s, r, a, d) =>
+                    html! {
+                        <div class="bg-gray-400 dark:bg-gray-600 dark:text-white rounded-md p-1 m-2"> { format!("Spun off {} {} per share with {}% of basis on {}", r.normalize(), s, a.normalize(), d.format("%Y-%m-%d")) } </div>
+                    },
+                tax::Information::TransferredIn(q, c, d) =>
+                    html! {
+                        <div class="bg-blue-400 dark:bg-blue-800 dark:text-white rounded-md p-1 m-2"> { format!("Transferred in: {} acquired {} for ${}", q.normalize(), d.format("%Y-%m-%d"), self.rounding.round(*c)) } </div>
+                    },
+                tax::Information::LostTransfer(transfer) =>
+                    html! {
+                        <div class="bg-red-200 dark:bg-red-600 dark:text-white w-80 p-1 ml-8"> { format!("{} from {} on {} left out (corporate actions line {})", transfer.quantity.normalize(), transfer.from, transfer.date.format("%Y-%m-%d"), transfer.row) } </div>
+                    },
+                tax::Information::Received(s, q, d) =>
+                    html! {
+                        <div class="bg-blue-400 dark:bg-blue-800 dark:text-white rounded-md p-1 m-2"> { format!("Received: {} from {} on {}", q.normalize(), s, d.format("%Y-%m-%d")) } </div>
+                    },
+                tax::Information::Cash(q, p, _d) =>
+                    html! {
+                        <div class="bg-indigo-400 dark:bg-indigo-800 dark:text-white rounded p-1 mt-2 mr-2 ml-2"> { format!("Paid in cash: {} at ${}", q.normalize(), p) } </div>
+                    },
+                tax::Information::WashBasis(a, q, d) =>
+                    html! {
+                        <div class="bg-yellow-200 dark:bg-yellow-600 dark:text-white w-80 p-1 ml-8"> { format!("+${} basis on {} shares from wash sale on {}", self.rounding.round(*a), q, d.format("%Y-%m-%d")) } </div>
                     },
-
-
-                _ => html! {}
             }
             }
             </div>
         }
     }
-    fn view_option_tax(data: (&String, &Vec<option_tax::Information>)) -> Html {
+
+    fn view_open_lot(&self, lot: &tax::OpenLot) -> Html {
+        html! {
+            <div class="w-96">
+                <div class="bg-gray-400 dark:bg-gray-600 dark:text-white rounded-md p-1 m-2"> { format!("Held: {} since {} for ${}", lot.quantity, lot.acquired.format("%Y-%m-%d"), self.rounding.round(lot.cost_basis)) } </div>
+            </div>
+        }
+    }
+
+    fn view_short_lot(&self, lot: &tax::ShortLot) -> Html {
+        html! {
+            <div class="w-96">
+                <div class="bg-red-300 dark:bg-red-700 dark:text-white rounded-md p-1 m-2"> { format!("Short: {} since {} for ${}", lot.quantity, lot.opened.format("%Y-%m-%d"), self.rounding.round(lot.proceeds - lot.fees)) } </div>
+            </div>
+        }
+    }
+
+    fn view_realized(&self, lot: &tax::RealizedLot) -> Html {
+        let gain = lot.proceeds - lot.cost_basis;
+        html! {
+            <div class="w-96">
+                <div class="w-80 bg-indigo-200 dark:bg-indigo-600 dark:text-white p-1 ml-8"> { format!("{} days -- {}", (lot.disposed - lot.acquired).num_days(), match lot.term { tax::Term::Short => "short-term capital", tax::Term::Long => "long-term capital" }) } </div>
+                if gain > Decimal::ZERO {
+                    <div class="w-64 bg-green-200 dark:bg-green-600 dark:text-white p-1 ml-24"> { format!("${}", self.rounding.round(gain))} </div>
+                }
+                else {
+                    <div class="w-64 bg-red-200 dark:bg-red-600 dark:text-white p-1 ml-24"> { format!("${}", self.rounding.round(gain))} </div>
+                }
+                <div class="bg-red-100 dark:bg-red-500 dark:text-white w-64 p-1 ml-24"> { format!("-${} (fees)", lot.fees) } </div>
+                if lot.transferred_in {
+                    <div class="bg-blue-200 dark:bg-blue-600 dark:text-white w-64 p-1 ml-24"> { "Transferred in, basis entered by hand" } </div>
+                }
+                { for lot.adjustments.iter().map(|adjustment| match adjustment {
+                    tax::Adjustment::WashSale(a, d) => html! {
+                        <div class="bg-yellow-200 dark:bg-yellow-600 dark:text-white w-64 p-1 ml-24"> { format!("Wash sale: ${} loss deferred to buy on {}", self.rounding.round(*a), d.format("%Y-%m-%d")) } </div>
+                    },
+                }) }
+            </div>
+        }
+    }
+
+    fn view_option_tax(&self, data: (&String, &Vec<option_tax::Information>)) -> Html {
         use option_tax::Information;
         let (symbol, information) = data;
 
-        let mut sum = 0.;
+        let mut sum = Decimal::ZERO;
         for info in information {
-            match info {
-                &Information::TotalDiff(a) => sum = a,
-                _ => (),
+            if let &Information::TotalDiff(a) = info {
+                sum = a
             }
         }
 
-        let color_class = if sum > 0. {
+        let color_class = if sum > Decimal::ZERO {
             "border-green-700"
-        } else if sum == 0.0 {
+        } else if sum.is_zero() {
             "border-gray-700"
         } else {
             "border-red-700"
@@ -519,7 +1331,7 @@ impl Model {
                 <div class="my-4 mx-4">
                     <h2 class="text-black dark:text-gray-200 text-2xl font-medium leading-tight"> {symbol}</h2>
                     <div class={classes!("bg-gray-200","dark:bg-gray-800", "border-l-8", color_class, "h-96", "overflow-y-auto", "overflow-x-hidden")}>
-                        {for information.iter().map(|f| Self::view_option_information(f))}
+                        {for information.iter().map(|data| self.view_option_information(data))}
 
                     </div>
                     if let Some(option_tax::Information::TotalDiff(q)) = information.last() {
@@ -530,7 +1342,35 @@ impl Model {
         }
     }
 
-    fn view_option_information(data: &option_tax::Information) -> Html {
+    fn view_strategy(&self, strategy: &option_tax::Strategy) -> Html {
+        let net = if strategy.net < Decimal::ZERO {
+            format!("${} debit", self.rounding.round(-strategy.net))
+        } else {
+            format!("${} credit", self.rounding.round(strategy.net))
+        };
+        let max_risk = match (strategy.max_risk, &strategy.kind) {
+            (Some(risk), _) => format!("${}", self.rounding.round(risk)),
+            (None, option_tax::StrategyKind::CoveredCall) => "covered by shares".to_string(),
+            (None, _) => "unlimited".to_string(),
+        };
+        html! {
+            <div class="m-2">
+                <div class="bg-blue-400 dark:bg-blue-800 dark:text-white rounded-md p-1"> { format!("{} on {}", strategy.kind, strategy.opened.format("%Y-%m-%d")) } </div>
+                <div class="w-80 bg-gray-300 dark:bg-gray-700 dark:text-white p-1 ml-8"> { format!("Opened for {}, max risk {}", net, max_risk) } </div>
+                if strategy.realized < Decimal::ZERO {
+                    <div class="w-64 bg-red-200 dark:bg-red-600 dark:text-white p-1 ml-24"> { format!("Realized: ${}", self.rounding.round(strategy.realized)) } </div>
+                } else {
+                    <div class="w-64 bg-green-200 dark:bg-green-600 dark:text-white p-1 ml-24"> { format!("Realized: ${}", self.rounding.round(strategy.realized)) } </div>
+                }
+                <details class="ml-8">
+                    <summary class="cursor-pointer dark:text-white"> { "Legs" } </summary>
+                    { for strategy.legs.iter().map(|data| self.view_option_information(data)) }
+                </details>
+            </div>
+        }
+    }
+
+    fn view_option_information(&self, data: &option_tax::Information) -> Html {
         html! {
             <div class="w-96">
             {
@@ -538,31 +1378,64 @@ impl Model {
                 option_tax::Information::BuyToOpen(p) =>
                     html! {
                         <>
-                        <div class="bg-blue-400 dark:bg-blue-800 dark:text-white rounded-md p-1 mx-2"> { format!("Buy to Open") } </div>
-                        <div class="w-64 bg-red-200 dark:bg-red-600 dark:text-white p-1 ml-24"> { format!("${}", (p*100.).round()/100.)} </div>
+                        <div class="bg-blue-400 dark:bg-blue-800 dark:text-white rounded-md p-1 mx-2"> { "Buy to Open" } </div>
+                        <div class="w-64 bg-red-200 dark:bg-red-600 dark:text-white p-1 ml-24"> { format!("${}", self.rounding.round(*p))} </div>
                         </>
                     },
                 option_tax::Information::BuyToClose(p) =>
                     html! {
                         <>
-                        <div class="bg-blue-400 dark:bg-blue-800 dark:text-white rounded-md p-1 mx-2"> { format!("Buy to Close") } </div>
-                        <div class="w-64 bg-red-200 dark:bg-red-600 dark:text-white p-1 ml-24"> { format!("${}", (p*100.).round()/100.)} </div>
+                        <div class="bg-blue-400 dark:bg-blue-800 dark:text-white rounded-md p-1 mx-2"> { "Buy to Close" } </div>
+                        <div class="w-64 bg-red-200 dark:bg-red-600 dark:text-white p-1 ml-24"> { format!("${}", self.rounding.round(*p))} </div>
                         </>
                     },
                 option_tax::Information::SellToOpen(p) =>
                     html! {
                         <>
-                        <div class="bg-indigo-400 dark:bg-indigo-800 dark:text-white rounded p-1 mt-2 mr-2 ml-2"> { format!("Sell to Open") } </div>
-                        <div class="w-64 bg-green-200 dark:bg-green-600 dark:text-white p-1 ml-24"> { format!("${}", (p*100.).round()/100.)} </div>
+                        <div class="bg-indigo-400 dark:bg-indigo-800 dark:text-white rounded p-1 mt-2 mr-2 ml-2"> { "Sell to Open" } </div>
+                        <div class="w-64 bg-green-200 dark:bg-green-600 dark:text-white p-1 ml-24"> { format!("${}", self.rounding.round(*p))} </div>
                         </>
                     },
                 option_tax::Information::SellToClose(p) =>
                     html! {
                         <>
-                        <div class="bg-indigo-400 dark:bg-indigo-800 dark:text-white rounded p-1 mt-2 mr-2 ml-2"> { format!("Sell to Close") } </div>
-                        <div class="w-64 bg-green-200 dark:bg-green-600 dark:text-white p-1 ml-24"> { format!("${}", (p*100.).round()/100.)} </div>
+                        <div class="bg-indigo-400 dark:bg-indigo-800 dark:text-white rounded p-1 mt-2 mr-2 ml-2"> { "Sell to Close" } </div>
+                        <div class="w-64 bg-green-200 dark:bg-green-600 dark:text-white p-1 ml-24"> { format!("${}", self.rounding.round(*p))} </div>
                         </>
                     },
+                option_tax::Information::Expired(q) =>
+                    html! {
+                        <div class="bg-gray-400 dark:bg-gray-600 dark:text-white rounded-md p-1 mx-2"> { format!("Expired: {}", q.normalize()) } </div>
+                    },
+                option_tax::Information::Exercised(q, p) =>
+                    html! {
+                        <div class="bg-gray-400 dark:bg-gray-600 dark:text-white rounded-md p-1 mx-2"> { format!("Exercised: {}, ${} premium moved to the stock", q.normalize(), self.rounding.round(*p)) } </div>
+                    },
+                option_tax::Information::Assigned(q, p) =>
+                    html! {
+                        <div class="bg-gray-400 dark:bg-gray-600 dark:text-white rounded-md p-1 mx-2"> { format!("Assigned: {}, ${} premium moved to the stock", q.normalize(), self.rounding.round(-*p)) } </div>
+                    },
+                option_tax::Information::Strategy(strategy) => self.view_strategy(strategy),
+                option_tax::Information::Realized(lot) =>
+                    html! {
+                        <div class="w-80 bg-indigo-200 dark:bg-indigo-600 dark:text-white p-1 ml-8"> { format!("{}: ${} {}", lot.symbol, self.rounding.round(lot.gain()), match lot.term { tax::Term::Short => "short-term", tax::Term::Long => "long-term" }) } </div>
+                    },
+                option_tax::Information::Section1256(lot) =>
+                    html! {
+                        <div class="w-80 bg-indigo-200 dark:bg-indigo-600 dark:text-white p-1 ml-8"> { format!("{}: ${} 60/40", lot.symbol, self.rounding.round(lot.gain())) } </div>
+                    },
+                option_tax::Information::UnmatchedClose(close) =>
+                    html! {
+                        <div class="bg-red-200 dark:bg-red-600 dark:text-white w-80 p-1 ml-8"> { format!("{} of {} closed not matched to any open (line {})", close.unmatched.normalize(), close.quantity.normalize(), close.row) } </div>
+                    },
+                option_tax::Information::MissingMark(missing) =>
+                    html! {
+                        <div class="bg-red-200 dark:bg-red-600 dark:text-white w-80 p-1 ml-8"> { format!("{} of {} not marked to market at the end of {}", missing.quantity.normalize(), missing.contract, missing.year) } </div>
+                    },
+                option_tax::Information::Marked(q, p) =>
+                    html! {
+                        <div class="bg-gray-400 dark:bg-gray-600 dark:text-white rounded-md p-1 mx-2"> { format!("Marked to market: {} at ${}", q.normalize(), p.round_dp(2)) } </div>
+                    },
 
 
                 _ => html! {}
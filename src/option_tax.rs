@@ -1,89 +1,816 @@
-use crate::csv_parser::OptionTrades;
+use crate::csv_parser::{OptionMark, OptionMarks, OptionTrade, OptionTrades, Trade, Trades};
+use crate::tax::{exchange_close, exchange_date, holding_term, RealizedLot, TaxYear, Term};
+use chrono::Datelike;
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
 use std::collections::HashMap;
 
+#[derive(Debug, Clone)]
 pub enum Information {
-    BuyToOpen(f64),
-    SellToOpen(f64),
-    BuyToClose(f64),
-    SellToClose(f64),
-    TotalDiff(f64),
+    BuyToOpen(Decimal),
+    SellToOpen(Decimal),
+    BuyToClose(Decimal),
+    SellToClose(Decimal),
+    // Realized gain of every closed position
+    TotalDiff(Decimal),
+    // This many contracts were still open when they expired
+    Expired(Decimal),
+    // This many bought contracts were exercised, and the premium that moved into the stock trade
+    Exercised(Decimal, Decimal),
+    // This many written contracts were assigned, and the premium that moved into the stock trade
+    Assigned(Decimal, Decimal),
+    // A closed position
+    Realized(RealizedLot),
+    // A closed position on a Section 1256 contract, taxed 60/40 on Form 6781 instead
+    Section1256(RealizedLot),
+    // This many contracts of a Section 1256 contract were marked to market at year end, and
+    // the price
+    Marked(Decimal, Decimal),
+    // Legs opened together, with everything that happened to them
+    Strategy(Strategy),
+    // A close of more contracts than were open, which the history is missing opens for
+    UnmatchedClose(UnmatchedClose),
+    // A Section 1256 contract held over a year end without a mark to market for it
+    MissingMark(MissingMark),
+}
+
+// Contracts held at the end of `year` with no mark of that year uploaded, so their gain for the
+// year is left out of Form 6781
+#[derive(Debug, Clone)]
+pub struct MissingMark {
+    pub contract: Contract,
+    pub year: i32,
+    pub quantity: Decimal,
+}
+
+// A close that could not be matched to open contracts, and where it is in the uploaded CSV
+#[derive(Debug, Clone)]
+pub struct UnmatchedClose {
+    pub row: u64,
+    pub contract: Contract,
+    pub date: chrono::DateTime<chrono::Utc>,
+    pub quantity: Decimal,
+    pub price: Decimal,
+    // contracts of the close no open was left for
+    pub unmatched: Decimal,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StrategyKind {
+    Single,
+    VerticalSpread,
+    CalendarSpread,
+    Straddle,
+    Strangle,
+    IronCondor,
+    IronButterfly,
+    CoveredCall,
+    // Anything else, by the broker's name for it
+    Other(String),
+}
+
+impl std::fmt::Display for StrategyKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            StrategyKind::Single => write!(f, "Single leg"),
+            StrategyKind::VerticalSpread => write!(f, "Vertical spread"),
+            StrategyKind::CalendarSpread => write!(f, "Calendar spread"),
+            StrategyKind::Straddle => write!(f, "Straddle"),
+            StrategyKind::Strangle => write!(f, "Strangle"),
+            StrategyKind::IronCondor => write!(f, "Iron condor"),
+            StrategyKind::IronButterfly => write!(f, "Iron butterfly"),
+            StrategyKind::CoveredCall => write!(f, "Covered call"),
+            StrategyKind::Other(name) => write!(f, "{}", name.replace('_', " ")),
+        }
+    }
+}
+
+// Option trades opened in one order, tracked as one unit
+#[derive(Debug, Clone)]
+pub struct Strategy {
+    pub kind: StrategyKind,
+    pub opened: chrono::DateTime<chrono::Utc>,
+    // Premium received when opening, negative for a debit
+    pub net: Decimal,
+    // The most it can lose at expiration, None when that is unlimited
+    pub max_risk: Option<Decimal>,
+    pub realized: Decimal,
+    pub legs: Vec<Information>,
+}
+
+// Works out the strategy from the shape of its legs
+fn classify(legs: &[&OptionTrade]) -> StrategyKind {
+    let name = legs[0].opening_strategy.clone().unwrap_or_default();
+    let calls = legs.iter().filter(|leg| leg.option_type == "call").count();
+    let bought = legs.iter().filter(|leg| leg.side == "buy").count();
+    let same_expiration = legs
+        .iter()
+        .all(|leg| leg.expiration_date == legs[0].expiration_date);
+    let strikes: Vec<Decimal> = legs.iter().map(|leg| leg.strike_price).collect();
+    match (legs.len(), calls, bought) {
+        (1, 1, 0) if name.contains("covered") => StrategyKind::CoveredCall,
+        (1, _, _) => StrategyKind::Single,
+        (2, 0 | 2, 1) if same_expiration && strikes[0] != strikes[1] => {
+            StrategyKind::VerticalSpread
+        }
+        (2, 0 | 2, 1) if !same_expiration && strikes[0] == strikes[1] => {
+            StrategyKind::CalendarSpread
+        }
+        (2, 1, 0 | 2) if same_expiration && strikes[0] == strikes[1] => StrategyKind::Straddle,
+        (2, 1, 0 | 2) if same_expiration => StrategyKind::Strangle,
+        (4, 2, 2) if same_expiration => {
+            let short = |option_type: &str| {
+                legs.iter()
+                    .find(|leg| leg.option_type == option_type && leg.side == "sell")
+                    .map(|leg| leg.strike_price)
+            };
+            if short("call").is_some() && short("call") == short("put") {
+                StrategyKind::IronButterfly
+            } else {
+                StrategyKind::IronCondor
+            }
+        }
+        _ => StrategyKind::Other(name),
+    }
+}
+
+// The most the legs can lose at expiration, from their payoff at each strike and at zero. The
+// payoff only changes slope at a strike, and keeps falling past the last one when more calls
+// were written than bought
+fn max_risk(legs: &[&OptionTrade], net: Decimal) -> Option<Decimal> {
+    let size = |leg: &OptionTrade| {
+        let size = leg.processed_quantity * leg.multiplier.unwrap_or(dec!(100));
+        if leg.side == "buy" {
+            size
+        } else {
+            -size
+        }
+    };
+    let payoff = |price: Decimal| {
+        net + legs
+            .iter()
+            .map(|leg| {
+                let intrinsic = if leg.option_type == "call" {
+                    price - leg.strike_price
+                } else {
+                    leg.strike_price - price
+                };
+                size(leg) * intrinsic.max(Decimal::ZERO)
+            })
+            .sum::<Decimal>()
+    };
+    let slope: Decimal = legs
+        .iter()
+        .filter(|leg| leg.option_type == "call")
+        .map(|leg| size(leg))
+        .sum();
+    if slope < Decimal::ZERO {
+        return None;
+    }
+    let worst = legs
+        .iter()
+        .map(|leg| payoff(leg.strike_price))
+        .fold(payoff(Decimal::ZERO), Decimal::min);
+    Some((-worst).max(Decimal::ZERO))
 }
 
 pub type AllOptionInfo = HashMap<String, Vec<Information>>;
 
+// Options exercised or assigned into a stock trade. Their premium is not an option gain, it is
+// part of the stock trade's cost (buys) or proceeds (sells) instead
+#[derive(Debug, Clone)]
+pub struct Exercise {
+    pub symbol: String,
+    // which of the symbol's stock trades it is
+    pub index: usize,
+    pub adjustment: Decimal,
+}
+
+// The stock trades with the premium of the options exercised or assigned into them folded into
+// their price
+pub fn fold_exercises(stocks: &Trades, exercises: &[Exercise]) -> Trades {
+    let mut stocks = stocks.clone();
+    for exercise in exercises {
+        if let Some(trade) = stocks
+            .get_mut(&exercise.symbol)
+            .and_then(|trades| trades.get_mut(exercise.index))
+        {
+            trade.average_price += exercise.adjustment / trade.quantity;
+        }
+    }
+    stocks
+}
+
+// Every unmatched close across all symbols
+pub fn unmatched_closes(info: &AllOptionInfo) -> impl Iterator<Item = &UnmatchedClose> {
+    info.values()
+        .flatten()
+        .filter_map(|information| match information {
+            Information::UnmatchedClose(close) => Some(close),
+            _ => None,
+        })
+}
+
+// Every missing year-end mark across all symbols
+pub fn missing_marks(info: &AllOptionInfo) -> impl Iterator<Item = &MissingMark> {
+    info.values()
+        .flatten()
+        .filter_map(|information| match information {
+            Information::MissingMark(missing) => Some(missing),
+            _ => None,
+        })
+}
+
+// Every realized position across all symbols
+pub fn realized(info: &AllOptionInfo) -> impl Iterator<Item = &RealizedLot> {
+    info.values()
+        .flatten()
+        .flat_map(|information| match information {
+            Information::Strategy(strategy) => strategy.legs.iter().collect(),
+            information => vec![information],
+        })
+        .filter_map(|information| match information {
+            Information::Realized(lot) => Some(lot),
+            _ => None,
+        })
+}
+
+// Every realized position on a Section 1256 contract across all symbols
+pub fn section_1256(info: &AllOptionInfo) -> impl Iterator<Item = &RealizedLot> {
+    info.values()
+        .flatten()
+        .flat_map(|information| match information {
+            Information::Strategy(strategy) => strategy.legs.iter().collect(),
+            information => vec![information],
+        })
+        .filter_map(|information| match information {
+            Information::Section1256(lot) => Some(lot),
+            _ => None,
+        })
+}
+
+// The contract a trade is on. Only trades on the same contract close each other
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Contract {
+    pub underlying: String,
+    pub expiration_date: chrono::NaiveDate,
+    pub strike_price: Decimal,
+    pub option_type: String,
+}
+
+impl Contract {
+    fn of(trade: &OptionTrade) -> Contract {
+        Contract {
+            underlying: trade.chain_symbol.clone(),
+            expiration_date: trade.expiration_date,
+            strike_price: trade.strike_price,
+            option_type: trade.option_type.clone(),
+        }
+    }
+}
+
+// e.g. "AAPL 2021-06-18 150 call"
+impl std::fmt::Display for Contract {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {} {}",
+            self.underlying,
+            self.expiration_date,
+            self.strike_price.normalize(),
+            self.option_type
+        )
+    }
+}
+
+// Cash paid or received for this many of a trade's contracts. The price is quoted per share
+fn premium(trade: &OptionTrade, quantity: Decimal) -> Decimal {
+    trade.price * trade.multiplier.unwrap_or(dec!(100)) * quantity
+}
+
+// The side of the stock trade that delivers the shares when an open trade is exercised or
+// assigned
+fn delivery_side(open: &OptionTrade) -> &'static str {
+    match (open.option_type.as_str(), open.side.as_str()) {
+        ("call", "buy") | ("put", "sell") => "buy",
+        _ => "sell",
+    }
+}
+
+// Closes this many contracts of an open trade for `closing` in cash, which is nothing when they
+// expire
+fn realize(
+    leg: &OpenLeg,
+    quantity: Decimal,
+    closing: Decimal,
+    closed_at: chrono::DateTime<chrono::Utc>,
+) -> RealizedLot {
+    let open = leg.trade;
+    // a bought option is sold to close, a written option is bought back. Gains and losses on
+    // written options are always short-term
+    let (proceeds, cost_basis, term) = if open.side == "buy" {
+        (
+            closing,
+            leg.premium(quantity),
+            holding_term(open.order_created_at, closed_at),
+        )
+    } else {
+        (leg.premium(quantity), closing, Term::Short)
+    };
+    RealizedLot {
+        symbol: Contract::of(open).to_string(),
+        quantity,
+        acquired: open.order_created_at,
+        disposed: closed_at,
+        proceeds,
+        cost_basis,
+        fees: Decimal::ZERO,
+        term,
+        adjustments: Vec::new(),
+        transferred_in: false,
+    }
+}
+
+// Contracts of an open trade that are still open
+struct OpenLeg<'a> {
+    trade: &'a OptionTrade,
+    quantity: Decimal,
+    // where its open is on the timeline
+    opened: usize,
+    // which strategy it is a leg of
+    strategy: usize,
+    // the price of its last year-end mark to market, which replaces the price it was opened at
+    marked: Option<Decimal>,
+}
+
+impl OpenLeg<'_> {
+    // Cash the leg was opened for, as of its last mark to market
+    fn premium(&self, quantity: Decimal) -> Decimal {
+        match self.marked {
+            Some(price) => price * self.trade.multiplier.unwrap_or(dec!(100)) * quantity,
+            None => premium(self.trade, quantity),
+        }
+    }
+}
+
+// The strategy of events that are not part of any, like a close without an open
+const NO_STRATEGY: usize = usize::MAX;
+
+// Brokers book the stock trade of an exercise or assignment this many days after expiration at
+// the latest, so a contract only counts as expired once they are over
+const DELIVERY_DAYS: i64 = 4;
+
+// Whether `stock` can be the delivery of a contract expiring on `expiration`. Before expiration
+// only a trade the broker marks as an exercise or assignment can
+fn delivers(stock: &Trade, expiration: chrono::NaiveDate) -> bool {
+    let date = exchange_date(stock.date);
+    let early = stock.order_type == "exercise" || stock.order_type == "assignment";
+    (early || date >= expiration) && date <= expiration + chrono::Duration::days(DELIVERY_DAYS)
+}
+
 pub fn parse(
     trades: &OptionTrades,
     symbol_filter: String,
-    year: Option<chrono::DateTime<chrono::Utc>>,
-) -> AllOptionInfo {
+    year: Option<TaxYear>,
+    now: chrono::DateTime<chrono::Utc>,
+    stocks: &Trades,
+    section_1256: &[String],
+    marks: &OptionMarks,
+) -> (AllOptionInfo, Vec<Exercise>) {
     let mut result = AllOptionInfo::new();
+    let mut exercises = Vec::new();
+    // every symbol is worked out, as its exercises change stock trades that may still be shown
     for (symbol, data) in trades {
-        if !symbol.contains(&symbol_filter) {
-            continue;
-        }
-
-        let mut informations = Vec::new();
+        // Everything that happened, next to the time it counts for and its strategy. An open
+        // counts when the last of its contracts is closed, so a position belongs to the tax year
+        // it was closed in
+        let mut timeline: Vec<(Option<chrono::DateTime<chrono::Utc>>, usize, Information)> =
+            Vec::new();
+        // a missing open throws off every later year too, so it is reported whatever the year
+        let mut problems = Vec::new();
 
         let mut data = data.clone();
         data.sort_by(|a, b| a.order_created_at.partial_cmp(&b.order_created_at).unwrap());
 
-        let mut stack = Vec::new();
+        // legs opened by the same order, which the broker stamps with the same time and strategy
+        let mut groups: Vec<Vec<&OptionTrade>> = Vec::new();
+        let mut group_of = HashMap::new();
+        for d in data.iter().filter(|d| d.opening_strategy.is_some()) {
+            let key = (d.order_created_at, d.opening_strategy.clone());
+            let group = *group_of.entry(key).or_insert_with(|| {
+                groups.push(Vec::new());
+                groups.len() - 1
+            });
+            groups[group].push(d);
+        }
+
+        // The open trades of every contract, oldest first. Only the filled part of an order
+        // counts
+        let mut stacks: HashMap<Contract, Vec<OpenLeg>> = HashMap::new();
 
-        for mut d in &data {
-            if let Some(opening_strategy) = &d.opening_strategy {
-                stack.push(d);
+        let mut stock_trades: Vec<(usize, &Trade)> = stocks
+            .get(symbol)
+            .map(|trades| trades.iter().enumerate().collect())
+            .unwrap_or_default();
+        stock_trades.sort_by_key(|(_, trade)| trade.date);
+        let mut stock_trades = stock_trades.into_iter().peekable();
 
-                let mut print = || {
-                    if d.side == "buy" {
-                        informations.push(Information::BuyToOpen(-d.price * 100.));
-                    } else {
-                        informations.push(Information::SellToOpen(d.price * 100.));
+        // Section 1256 contracts are marked to market at every year end, and their gains are
+        // split 60/40 no matter how long they were held
+        let is_1256 = section_1256.iter().any(|underlying| underlying == symbol);
+        let closed = |lot| {
+            if is_1256 {
+                Information::Section1256(lot)
+            } else {
+                Information::Realized(lot)
+            }
+        };
+        let mut marks: Vec<&OptionMark> = match marks.get(symbol) {
+            Some(marks) if is_1256 => marks.iter().collect(),
+            _ => Vec::new(),
+        };
+        marks.sort_by_key(|mark| mark.date);
+        // the year whose end is looked at next for contracts held over it without a mark
+        let mut year_end = data
+            .first()
+            .map_or(0, |d| exchange_date(d.order_created_at).year());
+        let is_marked = |contract: &Contract, year: i32| {
+            marks.iter().any(|mark| {
+                mark.date.year() == year
+                    && mark.chain_symbol == contract.underlying
+                    && mark.expiration_date == contract.expiration_date
+                    && mark.strike_price == contract.strike_price
+                    && mark.option_type == contract.option_type
+            })
+        };
+        let mut missing = Vec::new();
+        let mut marks = marks.iter().copied().peekable();
+
+        // one more round after the last trade for contracts that expired since
+        for i in 0..=data.len() {
+            let time = data.get(i).map_or(now, |d| d.order_created_at);
+
+            // the contracts still open now were held over every year end since the last round
+            while is_1256 && year_end < exchange_date(time).year() {
+                let last_day = chrono::NaiveDate::from_ymd_opt(year_end, 12, 31).unwrap();
+                for (contract, stack) in &stacks {
+                    if stack.is_empty()
+                        || contract.expiration_date <= last_day
+                        || is_marked(contract, year_end)
+                    {
+                        continue;
                     }
+                    missing.push(MissingMark {
+                        contract: contract.clone(),
+                        year: year_end,
+                        quantity: stack.iter().map(|leg| leg.quantity).sum(),
+                    });
+                }
+                year_end += 1;
+            }
+
+            // a stock trade at the strike of an open contract, for shares it delivers around its
+            // expiration, is that contract being exercised or assigned
+            while let Some((index, stock)) = stock_trades.next_if(|(_, stock)| stock.date <= time) {
+                // a row without shares delivers nothing
+                if stock.quantity.is_zero() {
+                    continue;
+                }
+                let found = stacks
+                    .iter_mut()
+                    .filter(|(contract, stack)| {
+                        let shares: Decimal = stack
+                            .iter()
+                            .map(|leg| leg.quantity * leg.trade.multiplier.unwrap_or(dec!(100)))
+                            .sum();
+                        !stack.is_empty()
+                            && contract.strike_price == stock.average_price
+                            && delivery_side(stack[0].trade) == stock.side
+                            && stock.quantity <= shares
+                            && delivers(stock, contract.expiration_date)
+                    })
+                    .min_by_key(|(contract, _)| contract.expiration_date);
+                let stack = match found {
+                    Some((_, stack)) => stack,
+                    None => continue,
                 };
-                if let Some(year) = year {
-                    if d.order_created_at > year {
-                        print();
+
+                // premium paid counts positive, premium received negative
+                let mut moved = Decimal::ZERO;
+                let mut contracts = Decimal::ZERO;
+                let mut shares = stock.quantity;
+                let assigned = stack[0].trade.side == "sell";
+                let strategy = stack[0].strategy;
+                while shares > Decimal::ZERO && !stack.is_empty() {
+                    let leg = &mut stack[0];
+                    let multiplier = leg.trade.multiplier.unwrap_or(dec!(100));
+                    let matched = leg.quantity.min(shares / multiplier);
+                    let cash = leg.premium(matched);
+                    moved += if leg.trade.side == "buy" { cash } else { -cash };
+                    contracts += matched;
+                    leg.quantity -= matched;
+                    shares -= matched * multiplier;
+                    if leg.quantity.is_zero() {
+                        timeline[leg.opened].0 = Some(stock.date);
+                        stack.remove(0);
                     }
-                } else {
-                    print();
                 }
+                // paid premium adds to the cost of bought shares and comes off the proceeds of
+                // sold ones
+                exercises.push(Exercise {
+                    symbol: symbol.clone(),
+                    index,
+                    adjustment: if stock.side == "buy" { moved } else { -moved },
+                });
+                let information = if assigned {
+                    Information::Assigned(contracts, moved)
+                } else {
+                    Information::Exercised(contracts, moved)
+                };
+                timeline.push((Some(stock.date), strategy, information));
             }
-            if let Some(closing_strategy) = &d.closing_strategy {
-                stack.pop();
-                let mut print = || {
-                    if d.side == "buy" {
-                        informations.push(Information::BuyToClose(-d.price * 100.));
-                    } else {
-                        informations.push(Information::SellToClose(d.price * 100.));
-                    }
+
+            // contracts still open after their expiration date and the days it can be delivered in
+            // expired worthless
+            let today = exchange_date(time);
+
+            // a mark to market realizes what the position is worth, and the rest of its gain is
+            // counted from that price on
+            while let Some(mark) = marks.next_if(|mark| mark.date < today) {
+                let marked_at = exchange_close(mark.date);
+                let contract = Contract {
+                    underlying: mark.chain_symbol.clone(),
+                    expiration_date: mark.expiration_date,
+                    strike_price: mark.strike_price,
+                    option_type: mark.option_type.clone(),
                 };
-                if let Some(year) = year {
-                    if d.order_created_at > year {
-                        print();
-                    }
+                for leg in stacks.get_mut(&contract).into_iter().flatten() {
+                    let multiplier = leg.trade.multiplier.unwrap_or(dec!(100));
+                    let value = mark.price * multiplier * leg.quantity;
+                    let lot = realize(leg, leg.quantity, value, marked_at);
+                    leg.marked = Some(mark.price);
+                    let information = Information::Marked(leg.quantity, mark.price);
+                    timeline.push((Some(marked_at), leg.strategy, information));
+                    timeline.push((Some(marked_at), leg.strategy, closed(lot)));
+                }
+            }
+
+            let mut expired: Vec<Contract> = stacks
+                .iter()
+                .filter(|(contract, stack)| {
+                    !stack.is_empty()
+                        && contract.expiration_date + chrono::Duration::days(DELIVERY_DAYS) < today
+                })
+                .map(|(contract, _)| contract.clone())
+                .collect();
+            expired.sort_by_key(|contract| (contract.expiration_date, contract.to_string()));
+            for contract in expired {
+                let expired_at = exchange_close(contract.expiration_date);
+                for leg in stacks.remove(&contract).unwrap_or_default() {
+                    timeline[leg.opened].0 = Some(expired_at);
+                    let expired = Information::Expired(leg.quantity);
+                    timeline.push((Some(expired_at), leg.strategy, expired));
+                    let lot = realize(&leg, leg.quantity, Decimal::ZERO, expired_at);
+                    timeline.push((Some(expired_at), leg.strategy, closed(lot)));
+                }
+            }
+
+            let d = match data.get(i) {
+                Some(d) => d,
+                None => break,
+            };
+            let stack = stacks.entry(Contract::of(d)).or_default();
+            let cash = premium(d, d.processed_quantity);
+            if d.opening_strategy.is_some() {
+                let strategy = group_of[&(d.order_created_at, d.opening_strategy.clone())];
+                stack.push(OpenLeg {
+                    trade: d,
+                    quantity: d.processed_quantity,
+                    opened: timeline.len(),
+                    strategy,
+                    marked: None,
+                });
+                if d.side == "buy" {
+                    timeline.push((None, strategy, Information::BuyToOpen(-cash)));
+                } else {
+                    timeline.push((None, strategy, Information::SellToOpen(cash)));
+                }
+            }
+            if d.closing_strategy.is_some() {
+                let closed_at = Some(d.order_created_at);
+                let strategy = stack.first().map_or(NO_STRATEGY, |leg| leg.strategy);
+                if d.side == "buy" {
+                    timeline.push((closed_at, strategy, Information::BuyToClose(-cash)));
                 } else {
-                    print();
+                    timeline.push((closed_at, strategy, Information::SellToClose(cash)));
+                }
+                // a close is matched to the oldest opens of its contract, with a realized lot for
+                // the part of each open it closes
+                let mut quantity = d.processed_quantity;
+                while quantity > Decimal::ZERO && !stack.is_empty() {
+                    let leg = &mut stack[0];
+                    let matched = leg.quantity.min(quantity);
+                    let lot = realize(leg, matched, premium(d, matched), d.order_created_at);
+                    timeline.push((closed_at, leg.strategy, closed(lot)));
+                    leg.quantity -= matched;
+                    quantity -= matched;
+                    if leg.quantity.is_zero() {
+                        timeline[leg.opened].0 = closed_at;
+                        stack.remove(0);
+                    }
+                }
+                if !quantity.is_zero() {
+                    problems.push(Information::UnmatchedClose(UnmatchedClose {
+                        row: d.row,
+                        contract: Contract::of(d),
+                        date: d.order_created_at,
+                        quantity: d.processed_quantity,
+                        price: d.price,
+                        unmatched: quantity,
+                    }));
                 }
             }
         }
-        let mut total_diff = 0.;
-        for info in &informations {
-            match info {
-                Information::BuyToOpen(p)
-                | Information::SellToOpen(p)
-                | Information::BuyToClose(p)
-                | Information::SellToClose(p) => total_diff += p,
-                _ => {}
+
+        // with a tax year picked, positions still open belong to no year yet
+        let mut legs: Vec<Vec<Information>> = groups.iter().map(|_| Vec::new()).collect();
+        let mut unmatched = Vec::new();
+        for (counts_at, strategy, information) in timeline {
+            let in_year = match (year, counts_at) {
+                (None, _) => true,
+                (Some(year), Some(counts_at)) => year.contains(counts_at),
+                (Some(_), None) => false,
+            };
+            if in_year {
+                legs.get_mut(strategy)
+                    .unwrap_or(&mut unmatched)
+                    .push(information);
             }
         }
+        let realized = |legs: &[Information]| {
+            legs.iter()
+                .map(|information| match information {
+                    Information::Realized(lot) | Information::Section1256(lot) => lot.gain(),
+                    _ => Decimal::ZERO,
+                })
+                .sum::<Decimal>()
+        };
+        let mut informations: Vec<Information> = groups
+            .iter()
+            .zip(legs)
+            .filter(|(_, legs)| !legs.is_empty())
+            .map(|(group, legs)| {
+                let net = group
+                    .iter()
+                    .map(|leg| {
+                        let cash = premium(leg, leg.processed_quantity);
+                        if leg.side == "buy" {
+                            -cash
+                        } else {
+                            cash
+                        }
+                    })
+                    .sum();
+                Information::Strategy(Strategy {
+                    kind: classify(group),
+                    opened: group[0].order_created_at,
+                    net,
+                    max_risk: max_risk(group, net),
+                    realized: realized(&legs),
+                    legs,
+                })
+            })
+            .collect();
+        let total_diff = informations
+            .iter()
+            .map(|information| match information {
+                Information::Strategy(strategy) => strategy.realized,
+                _ => Decimal::ZERO,
+            })
+            .sum();
+        informations.append(&mut unmatched);
+        informations.append(&mut problems);
+        missing.sort_by_key(|missing| (missing.year, missing.contract.to_string()));
+        informations.extend(missing.into_iter().map(Information::MissingMark));
         informations.push(Information::TotalDiff(total_diff));
-        if informations.len() > 0 {
+        if symbol.contains(&symbol_filter) {
             result
                 .entry(symbol.to_string())
-                .or_insert(Vec::new())
+                .or_default()
                 .append(&mut informations);
         }
     }
 
-    result
+    (result, exercises)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csv_parser;
+    use chrono::TimeZone;
+
+    // A call bought on 2024-03-01 expiring on Friday 2024-03-15 at a strike of 10, and the stock
+    // trades in `stocks`
+    fn exercises(stocks: &str) -> (AllOptionInfo, Vec<Exercise>) {
+        let options = csv_parser::parse_options(
+            "chain_symbol,expiration_date,strike_price,option_type,side,order_created_at,\
+             direction,order_quantity,order_type,opening_strategy,closing_strategy,price,\
+             processed_quantity\n\
+             A,2024-03-15,10,call,buy,2024-03-01T15:00:00Z,debit,1,limit,long_call,,1.00,1\n",
+        )
+        .unwrap();
+        let header = "symbol,date,order_type,side,fees,quantity,average_price\n";
+        let stocks = csv_parser::parse(&format!("{}{}", header, stocks)).unwrap();
+        let now = chrono::Utc.with_ymd_and_hms(2024, 4, 1, 0, 0, 0).unwrap();
+        parse(
+            &options,
+            String::new(),
+            None,
+            now,
+            &stocks,
+            &[],
+            &csv_parser::OptionMarks::new(),
+        )
+    }
+
+    fn expired(info: &AllOptionInfo) -> bool {
+        info["A"].iter().any(|information| match information {
+            Information::Strategy(strategy) => strategy
+                .legs
+                .iter()
+                .any(|leg| matches!(leg, Information::Expired(_))),
+            _ => false,
+        })
+    }
+
+    #[test]
+    fn delivery_booked_after_expiration() {
+        let (info, exercises) = exercises("A,2024-03-18T15:00:00Z,market,buy,0,100,10\n");
+        assert_eq!(exercises.len(), 1);
+        assert_eq!(exercises[0].adjustment, dec!(100));
+        assert!(!expired(&info));
+    }
+
+    #[test]
+    fn trade_at_the_strike_before_expiration() {
+        let (info, exercises) = exercises("A,2024-03-05T15:00:00Z,market,buy,0,100,10\n");
+        assert!(exercises.is_empty());
+        assert!(expired(&info));
+    }
+
+    #[test]
+    fn early_exercise() {
+        let (info, exercises) = exercises("A,2024-03-05T15:00:00Z,exercise,buy,0,100,10\n");
+        assert_eq!(exercises.len(), 1);
+        assert!(!expired(&info));
+    }
+
+    #[test]
+    fn stock_row_without_shares() {
+        let stocks = "A,2024-03-18T15:00:00Z,market,buy,0,0,10\n";
+        let (info, exercises) = exercises(stocks);
+        assert!(exercises.is_empty());
+        assert!(expired(&info));
+    }
+
+    // An SPX call held from 2021-12-01 over the year end until it expires in March, with the
+    // marks in `marks`
+    fn held_over_year_end(marks: &str) -> Vec<MissingMark> {
+        let options = csv_parser::parse_options(
+            "chain_symbol,expiration_date,strike_price,option_type,side,order_created_at,\
+             direction,order_quantity,order_type,opening_strategy,closing_strategy,price,\
+             processed_quantity\n\
+             SPX,2022-03-18,4500,call,buy,2021-12-01T15:00:00Z,debit,2,limit,long_call,,10,2\n",
+        )
+        .unwrap();
+        let marks = csv_parser::parse_option_marks(&format!(
+            "chain_symbol,expiration_date,strike_price,option_type,date,price\n{}",
+            marks
+        ))
+        .unwrap();
+        let now = chrono::Utc.with_ymd_and_hms(2022, 4, 1, 0, 0, 0).unwrap();
+        let (info, _) = parse(
+            &options,
+            String::new(),
+            None,
+            now,
+            &Trades::new(),
+            &["SPX".to_string()],
+            &marks,
+        );
+        missing_marks(&info).cloned().collect()
+    }
+
+    #[test]
+    fn year_end_without_a_mark() {
+        let missing = held_over_year_end("");
+        assert_eq!(missing.len(), 1);
+        assert_eq!((missing[0].year, missing[0].quantity), (2021, dec!(2)));
+    }
+
+    #[test]
+    fn year_end_with_a_mark() {
+        assert!(held_over_year_end("SPX,2022-03-18,4500,call,2021-12-31,12\n").is_empty());
+    }
 }
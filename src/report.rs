@@ -0,0 +1,308 @@
+use crate::tax::{exchange_date, Adjustment, RealizedLot, Term};
+use rust_decimal::{Decimal, RoundingStrategy};
+
+// How a sale was reported by the broker, which decides its Form 8949 box
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reporting {
+    // On a 1099-B with the cost basis reported to the IRS (box A or D)
+    Covered,
+    // On a 1099-B without the cost basis, like shares the broker got from another one (box B or E)
+    NoncoveredReported,
+    // Not on any 1099-B, like most crypto (box C or F)
+    Unreported,
+}
+
+// How amounts are rounded on the forms. The IRS allows whole dollars as long as it is done
+// on every line
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rounding {
+    Cents,
+    WholeDollars,
+}
+
+impl Rounding {
+    pub fn round(self, amount: Decimal) -> Decimal {
+        let places = match self {
+            Rounding::Cents => 2,
+            Rounding::WholeDollars => 0,
+        };
+        amount.round_dp_with_strategy(places, RoundingStrategy::MidpointAwayFromZero)
+    }
+}
+
+// One line of Form 8949
+#[derive(Debug, Clone)]
+pub struct Row {
+    pub description: String,
+    pub acquired: chrono::NaiveDate,
+    pub sold: chrono::NaiveDate,
+    pub proceeds: Decimal,
+    pub cost_basis: Decimal,
+    pub code: String,
+    pub adjustment: Decimal,
+    pub gain: Decimal,
+    // Part I for short-term, Part II for long-term
+    pub term: Term,
+    pub form_box: char,
+    // the basis came from a lot transferred in, not from the broker's own records
+    pub transferred_in: bool,
+}
+
+pub fn form_8949<'a>(
+    lots: impl Iterator<Item = &'a RealizedLot>,
+    reporting: Reporting,
+    rounding: Rounding,
+) -> Vec<Row> {
+    let mut rows: Vec<Row> = lots
+        .map(|lot| {
+            let mut code: Vec<&str> = lot
+                .adjustments
+                .iter()
+                .map(|adjustment| match adjustment {
+                    Adjustment::WashSale(..) => "W",
+                })
+                .collect();
+            code.dedup();
+            // the broker has no basis of its own for shares transferred in
+            let reporting = match reporting {
+                Reporting::Covered if lot.transferred_in => Reporting::NoncoveredReported,
+                reporting => reporting,
+            };
+            let form_box = match (lot.term, reporting) {
+                (Term::Short, Reporting::Covered) => 'A',
+                (Term::Short, Reporting::NoncoveredReported) => 'B',
+                (Term::Short, Reporting::Unreported) => 'C',
+                (Term::Long, Reporting::Covered) => 'D',
+                (Term::Long, Reporting::NoncoveredReported) => 'E',
+                (Term::Long, Reporting::Unreported) => 'F',
+            };
+            // proceeds are reported net of selling fees. The gain is worked out from the rounded
+            // columns so every line adds up the way the form does it
+            let proceeds = rounding.round(lot.proceeds - lot.fees);
+            let cost_basis = rounding.round(lot.cost_basis);
+            let adjustment = rounding.round(lot.adjustment());
+            Row {
+                description: format!("{} {}", lot.quantity.normalize(), lot.symbol),
+                acquired: exchange_date(lot.acquired),
+                sold: exchange_date(lot.disposed),
+                proceeds,
+                cost_basis,
+                code: code.concat(),
+                adjustment,
+                gain: proceeds - cost_basis + adjustment,
+                term: lot.term,
+                form_box,
+                transferred_in: lot.transferred_in,
+            }
+        })
+        .collect();
+    rows.sort_by_key(|row| (row.form_box, row.sold));
+    rows
+}
+
+// Form 6781 Part I: the net gain on Section 1256 contracts, taxed 40% short-term and 60%
+// long-term whatever the holding period
+#[derive(Debug, Clone, Default)]
+pub struct Form6781 {
+    pub gain: Decimal,
+    pub short_term: Decimal,
+    pub long_term: Decimal,
+}
+
+pub fn form_6781<'a>(lots: impl Iterator<Item = &'a RealizedLot>, rounding: Rounding) -> Form6781 {
+    let gain = rounding.round(lots.map(RealizedLot::gain).sum());
+    // the long-term part is what is left, so the two parts always add up to the gain
+    let short_term = rounding.round(gain * Decimal::new(4, 1));
+    Form6781 {
+        gain,
+        short_term,
+        long_term: gain - short_term,
+    }
+}
+
+// One line of Schedule D, with its columns (d), (e), (g) and (h)
+#[derive(Debug, Clone)]
+pub struct Line {
+    pub line: &'static str,
+    pub description: &'static str,
+    pub proceeds: Decimal,
+    pub cost_basis: Decimal,
+    pub adjustment: Decimal,
+    pub gain: Decimal,
+}
+
+pub fn schedule_d(rows: &[Row], form_6781: &Form6781) -> Vec<Line> {
+    let total = |line, description, filter: &dyn Fn(&Row) -> bool| {
+        let mut total = Line {
+            line,
+            description,
+            proceeds: Decimal::ZERO,
+            cost_basis: Decimal::ZERO,
+            adjustment: Decimal::ZERO,
+            gain: Decimal::ZERO,
+        };
+        for row in rows.iter().filter(|row| filter(row)) {
+            total.proceeds += row.proceeds;
+            total.cost_basis += row.cost_basis;
+            total.adjustment += row.adjustment;
+            total.gain += row.gain;
+        }
+        total
+    };
+    // Form 6781 only fills in the gain column
+    let from_6781 = |line, description, gain| Line {
+        line,
+        description,
+        proceeds: Decimal::ZERO,
+        cost_basis: Decimal::ZERO,
+        adjustment: Decimal::ZERO,
+        gain,
+    };
+    let mut short_term = total("7", "Net short-term capital gain or (loss)", &|row| {
+        row.term == Term::Short
+    });
+    short_term.gain += form_6781.short_term;
+    let mut long_term = total("15", "Net long-term capital gain or (loss)", &|row| {
+        row.term == Term::Long
+    });
+    long_term.gain += form_6781.long_term;
+    let mut combined = total("16", "Combined short-term and long-term", &|_| true);
+    combined.gain += form_6781.gain;
+    vec![
+        total("1b", "Short-term from Form 8949 box A", &|row| {
+            row.form_box == 'A'
+        }),
+        total("2", "Short-term from Form 8949 box B", &|row| {
+            row.form_box == 'B'
+        }),
+        total("3", "Short-term from Form 8949 box C", &|row| {
+            row.form_box == 'C'
+        }),
+        from_6781("4", "Short-term from Form 6781", form_6781.short_term),
+        short_term,
+        total("8b", "Long-term from Form 8949 box D", &|row| {
+            row.form_box == 'D'
+        }),
+        total("9", "Long-term from Form 8949 box E", &|row| {
+            row.form_box == 'E'
+        }),
+        total("10", "Long-term from Form 8949 box F", &|row| {
+            row.form_box == 'F'
+        }),
+        from_6781("11", "Long-term from Form 6781", form_6781.long_term),
+        long_term,
+        combined,
+    ]
+}
+
+pub fn to_csv(rows: &[Row]) -> Result<String, csv::Error> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record([
+        "part",
+        "box",
+        "description",
+        "date acquired",
+        "date sold",
+        "proceeds",
+        "cost basis",
+        "code",
+        "adjustment",
+        "gain or loss",
+        "transferred in",
+    ])?;
+    for row in rows {
+        writer.write_record(&[
+            match row.term {
+                Term::Short => "I".to_string(),
+                Term::Long => "II".to_string(),
+            },
+            row.form_box.to_string(),
+            row.description.clone(),
+            row.acquired.format("%m/%d/%Y").to_string(),
+            row.sold.format("%m/%d/%Y").to_string(),
+            format!("{:.2}", row.proceeds),
+            format!("{:.2}", row.cost_basis),
+            row.code.clone(),
+            format!("{:.2}", row.adjustment),
+            format!("{:.2}", row.gain),
+            if row.transferred_in { "yes" } else { "" }.to_string(),
+        ])?;
+    }
+    let bytes = writer.into_inner().map_err(|e| e.into_error())?;
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+// A standalone page with every Form 8949 box as its own table, followed by the Form 6781 and
+// Schedule D totals
+pub fn to_html(rows: &[Row], form_6781: &Form6781, lines: &[Line]) -> String {
+    let mut html = String::from(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>Form 8949 and Schedule D</title>\
+         <style>body{font-family:sans-serif}table{border-collapse:collapse;margin-bottom:2em}\
+         th,td{border:1px solid #999;padding:2px 6px}td.n{text-align:right}</style></head><body>",
+    );
+    for form_box in ['A', 'B', 'C', 'D', 'E', 'F'] {
+        let rows: Vec<&Row> = rows.iter().filter(|row| row.form_box == form_box).collect();
+        if rows.is_empty() {
+            continue;
+        }
+        let part = if form_box < 'D' {
+            "Part I (short-term)"
+        } else {
+            "Part II (long-term)"
+        };
+        html += &format!(
+            "<h2>Form 8949 {}, box {}</h2><table><tr><th>(a) Description</th>\
+             <th>(b) Date acquired</th><th>(c) Date sold</th><th>(d) Proceeds</th>\
+             <th>(e) Cost basis</th><th>(f) Code</th><th>(g) Adjustment</th>\
+             <th>(h) Gain or (loss)</th></tr>",
+            part, form_box
+        );
+        for row in rows {
+            html += &format!(
+                "<tr><td>{}{}</td><td>{}</td><td>{}</td><td class=\"n\">{:.2}</td>\
+                 <td class=\"n\">{:.2}</td><td>{}</td><td class=\"n\">{:.2}</td>\
+                 <td class=\"n\">{:.2}</td></tr>",
+                escape(&row.description),
+                if row.transferred_in {
+                    " (transferred in)"
+                } else {
+                    ""
+                },
+                row.acquired.format("%m/%d/%Y"),
+                row.sold.format("%m/%d/%Y"),
+                row.proceeds,
+                row.cost_basis,
+                row.code,
+                row.adjustment,
+                row.gain
+            );
+        }
+        html += "</table>";
+    }
+    if !form_6781.gain.is_zero() {
+        html += &format!(
+            "<h2>Form 6781 Part I</h2><table><tr><td>Net gain or (loss) on Section 1256 \
+             contracts</td><td class=\"n\">{:.2}</td></tr><tr><td>Short-term (40%)</td>\
+             <td class=\"n\">{:.2}</td></tr><tr><td>Long-term (60%)</td>\
+             <td class=\"n\">{:.2}</td></tr></table>",
+            form_6781.gain, form_6781.short_term, form_6781.long_term
+        );
+    }
+    html += "<h2>Schedule D</h2><table><tr><th>Line</th><th></th><th>(d) Proceeds</th>\
+             <th>(e) Cost basis</th><th>(g) Adjustments</th><th>(h) Gain or (loss)</th></tr>";
+    for line in lines {
+        html += &format!(
+            "<tr><td>{}</td><td>{}</td><td class=\"n\">{:.2}</td><td class=\"n\">{:.2}</td>\
+             <td class=\"n\">{:.2}</td><td class=\"n\">{:.2}</td></tr>",
+            line.line, line.description, line.proceeds, line.cost_basis, line.adjustment, line.gain
+        );
+    }
+    html += "</table></body></html>";
+    html
+}
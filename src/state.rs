@@ -0,0 +1,250 @@
+use crate::capital_gains::{FilingStatus, YearSummary};
+use crate::federal::{progressive, status_key};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+
+// Where the taxpayer lives. Each state with an income tax has a table per tax year
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+    // Alaska, Florida, Nevada, Texas and the other states without an income tax
+    NoStateTax,
+    California,
+    Illinois,
+    Massachusetts,
+    Pennsylvania,
+    // no income tax, but a tax on long-term gains
+    Washington,
+}
+
+impl std::fmt::Display for State {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            State::NoStateTax => "No state tax",
+            State::California => "California",
+            State::Illinois => "Illinois",
+            State::Massachusetts => "Massachusetts",
+            State::Pennsylvania => "Pennsylvania",
+            State::Washington => "Washington",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+const TABLES: &[(State, i32, &str)] = &[
+    (
+        State::California,
+        2024,
+        include_str!("../data/state/california/2024.csv"),
+    ),
+    (
+        State::Illinois,
+        2024,
+        include_str!("../data/state/illinois/2024.csv"),
+    ),
+    (
+        State::Massachusetts,
+        2024,
+        include_str!("../data/state/massachusetts/2024.csv"),
+    ),
+    (
+        State::Pennsylvania,
+        2024,
+        include_str!("../data/state/pennsylvania/2024.csv"),
+    ),
+    (
+        State::Washington,
+        2024,
+        include_str!("../data/state/washington/2024.csv"),
+    ),
+    (
+        State::Washington,
+        2025,
+        include_str!("../data/state/washington/2025.csv"),
+    ),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Kind {
+    StandardDeduction,
+    // rates on taxable income, which includes the gains a state has no rates of its own for
+    Ordinary,
+    // rates on short-term gains taxed apart from other income
+    ShortTerm,
+    // rates on long-term gains taxed apart from other income
+    LongTerm,
+    // extra rate on all income over a threshold
+    Surtax,
+    // how much of a net capital loss comes off other income, in `over`, for a state that does
+    // not follow the federal limit
+    LossLimit,
+}
+
+// One line of a state table: `rate` applies to income over `over`. For the standard deduction or
+// exemption `over` is the amount itself
+#[derive(Debug, Clone, Deserialize)]
+struct TableRow {
+    filing_status: String,
+    kind: Kind,
+    over: Decimal,
+    rate: Decimal,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Brackets {
+    standard_deduction: Decimal,
+    ordinary: Vec<(Decimal, Decimal)>,
+    short_term: Vec<(Decimal, Decimal)>,
+    long_term: Vec<(Decimal, Decimal)>,
+    surtax: Vec<(Decimal, Decimal)>,
+    loss_limit: Option<Decimal>,
+}
+
+// The table of `state` for `year`, or the latest year before it when it is not out yet, and
+// which year that is. There is none for years before the first table
+fn brackets(
+    state: State,
+    year: i32,
+    status: FilingStatus,
+) -> Result<Option<(i32, Brackets)>, csv::Error> {
+    let table = TABLES
+        .iter()
+        .rev()
+        .find(|(table_state, table_year, _)| *table_state == state && *table_year <= year);
+    let (table_year, table) = match table {
+        Some((_, table_year, table)) => (*table_year, table),
+        None => return Ok(None),
+    };
+    let mut brackets = Brackets::default();
+    let mut reader = csv::Reader::from_reader(table.as_bytes());
+    for row in reader.deserialize() {
+        let row: TableRow = row?;
+        if row.filing_status != status_key(status) {
+            continue;
+        }
+        match row.kind {
+            Kind::StandardDeduction => brackets.standard_deduction = row.over,
+            Kind::Ordinary => brackets.ordinary.push((row.over, row.rate)),
+            Kind::ShortTerm => brackets.short_term.push((row.over, row.rate)),
+            Kind::LongTerm => brackets.long_term.push((row.over, row.rate)),
+            Kind::Surtax => brackets.surtax.push((row.over, row.rate)),
+            Kind::LossLimit => brackets.loss_limit = Some(row.over),
+        }
+    }
+    Ok(Some((table_year, brackets)))
+}
+
+// The gains are netted and a net loss is limited to `limit`, which is the federal one unless the
+// state's table has its own
+fn compute(
+    brackets: &Brackets,
+    limit: Decimal,
+    ordinary_income: Decimal,
+    net_short_term: Decimal,
+    net_long_term: Decimal,
+) -> Decimal {
+    let net = net_short_term + net_long_term;
+    let (short_term, long_term, loss) = if net < Decimal::ZERO {
+        (Decimal::ZERO, Decimal::ZERO, (-net).min(limit))
+    } else {
+        let long_term = net_long_term.min(net).max(Decimal::ZERO);
+        (net - long_term, long_term, Decimal::ZERO)
+    };
+    let income = ordinary_income - loss + short_term + long_term;
+
+    let mut ordinary = ordinary_income - loss;
+    if brackets.short_term.is_empty() {
+        ordinary += short_term;
+    }
+    if brackets.long_term.is_empty() {
+        ordinary += long_term;
+    }
+    let ordinary = (ordinary - brackets.standard_deduction).max(Decimal::ZERO);
+    let gains = |brackets: &[(Decimal, Decimal)], gain| progressive(brackets, Decimal::ZERO, gain);
+    progressive(&brackets.ordinary, Decimal::ZERO, ordinary)
+        + gains(&brackets.short_term, short_term)
+        + gains(&brackets.long_term, long_term)
+        + progressive(&brackets.surtax, Decimal::ZERO, income.max(Decimal::ZERO))
+}
+
+#[derive(Debug, Clone)]
+pub struct Estimate {
+    pub state: State,
+    // the tax year whose table was used
+    pub table_year: i32,
+    pub tax: Decimal,
+    // how much of the tax there would not be without the gains and losses
+    pub on_gains: Decimal,
+}
+
+// The state tax owed for a year of gains on top of other ordinary income. None when the state
+// has no tables for the year or any year before it
+pub fn estimate(
+    state: State,
+    summary: &YearSummary,
+    status: FilingStatus,
+    ordinary_income: Decimal,
+) -> Result<Option<Estimate>, csv::Error> {
+    let (table_year, brackets) = match brackets(state, summary.year, status)? {
+        Some(brackets) => brackets,
+        None => return Ok(None),
+    };
+    let limit = brackets
+        .loss_limit
+        .unwrap_or_else(|| status.capital_loss_limit());
+    let tax = compute(
+        &brackets,
+        limit,
+        ordinary_income,
+        summary.net_short_term,
+        summary.net_long_term,
+    );
+    let without_gains = compute(
+        &brackets,
+        limit,
+        ordinary_income,
+        Decimal::ZERO,
+        Decimal::ZERO,
+    );
+    Ok(Some(Estimate {
+        state,
+        table_year,
+        tax,
+        on_gains: tax - without_gains,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    fn on_gains(state: State, net_short_term: Decimal) -> Decimal {
+        let summary = YearSummary {
+            year: 2024,
+            net_short_term,
+            ..Default::default()
+        };
+        estimate(state, &summary, FilingStatus::Single, dec!(50000))
+            .unwrap()
+            .unwrap()
+            .on_gains
+    }
+
+    #[test]
+    fn pennsylvania_loss_does_not_offset_other_income() {
+        assert_eq!(on_gains(State::Pennsylvania, dec!(-10000)), dec!(0));
+    }
+
+    #[test]
+    fn massachusetts_loss_limit() {
+        // $2,000 of the loss comes off, at 5%
+        assert_eq!(on_gains(State::Massachusetts, dec!(-10000)), dec!(-100));
+    }
+
+    #[test]
+    fn federal_loss_limit_elsewhere() {
+        // $3,000 of the loss comes off, at 4.95%
+        assert_eq!(on_gains(State::Illinois, dec!(-10000)), dec!(-148.5));
+    }
+}
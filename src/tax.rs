@@ -1,144 +1,1490 @@
-use crate::csv_parser::Trades;
+use crate::csv_parser::{
+    ActionKind, CorporateAction, CorporateActions, LotSelections, OpeningLots, Trades,
+};
+use chrono::{Datelike, TimeZone};
+use rust_decimal::Decimal;
 use std::collections::HashMap;
 
+// Trades are dated by the calendar of the exchange they happened on
+pub const EXCHANGE_TIMEZONE: chrono_tz::Tz = chrono_tz::America::New_York;
+
+// A tax year: a calendar year in some timezone, from the start of January 1st up to but not
+// including the start of the next January 1st
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TaxYear {
+    pub start: chrono::DateTime<chrono::Utc>,
+    pub end: chrono::DateTime<chrono::Utc>,
+}
+
+impl TaxYear {
+    pub fn calendar(year: i32, timezone: chrono_tz::Tz) -> Option<TaxYear> {
+        let start = |year| {
+            timezone
+                .with_ymd_and_hms(year, 1, 1, 0, 0, 0)
+                .single()
+                .map(|start| start.with_timezone(&chrono::Utc))
+        };
+        Some(TaxYear {
+            start: start(year)?,
+            end: start(year + 1)?,
+        })
+    }
+
+    pub fn contains(&self, date: chrono::DateTime<chrono::Utc>) -> bool {
+        date >= self.start && date < self.end
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Term {
+    Short,
+    Long,
+}
+
+// The calendar date of a trade on the exchange
+pub fn exchange_date(date: chrono::DateTime<chrono::Utc>) -> chrono::NaiveDate {
+    date.with_timezone(&EXCHANGE_TIMEZONE).date_naive()
+}
+
+// When trading opens on a date, which is when corporate actions take effect
+fn exchange_open(date: chrono::NaiveDate) -> chrono::DateTime<chrono::Utc> {
+    EXCHANGE_TIMEZONE
+        .from_local_datetime(&date.and_hms_opt(9, 30, 0).unwrap())
+        .unwrap()
+        .with_timezone(&chrono::Utc)
+}
+
+// When trading closes on a date, which is when options expire
+pub fn exchange_close(date: chrono::NaiveDate) -> chrono::DateTime<chrono::Utc> {
+    EXCHANGE_TIMEZONE
+        .from_local_datetime(&date.and_hms_opt(16, 0, 0).unwrap())
+        .unwrap()
+        .with_timezone(&chrono::Utc)
+}
+
+// A lot is long-term when held for more than one year. The holding period starts the day after
+// acquisition, so selling on the anniversary itself is still short-term. A lot bought on February
+// 29th has its anniversary on February 28th
+pub fn holding_term(
+    acquired: chrono::DateTime<chrono::Utc>,
+    disposed: chrono::DateTime<chrono::Utc>,
+) -> Term {
+    if exchange_date(disposed) >= long_term_from(acquired) {
+        Term::Long
+    } else {
+        Term::Short
+    }
+}
+
+// The first day a sale of a lot is long-term
+pub fn long_term_from(acquired: chrono::DateTime<chrono::Utc>) -> chrono::NaiveDate {
+    let acquired = exchange_date(acquired);
+    let anniversary = acquired
+        .with_year(acquired.year() + 1)
+        .or_else(|| chrono::NaiveDate::from_ymd_opt(acquired.year() + 1, 2, 28))
+        .unwrap();
+    anniversary.succ_opt().unwrap()
+}
+
+// A buy replaces shares sold at a loss when it is made within 30 days before or after the sale.
+// The days are calendar days on the exchange, not 24 hour periods
+pub fn in_wash_window(
+    sold: chrono::DateTime<chrono::Utc>,
+    bought: chrono::DateTime<chrono::Utc>,
+) -> bool {
+    (exchange_date(bought) - exchange_date(sold))
+        .num_days()
+        .abs()
+        <= 30
+}
+
+// Which of the held buys a sell consumes first
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LotMethod {
+    // First in, first out (the IRS default)
+    Fifo,
+    // Last in, first out
+    Lifo,
+    // Highest cost first
+    Hifo,
+    // Lowest cost first
+    Lofo,
+    // Lots picked per sale from the lot selections, FIFO for anything not picked
+    Specific,
+}
+
+// One lot selection of a sell: shares bought on a date, and how many of them are still to be
+// matched. None matches every share bought that day. The date is the day of the buy, also for a
+// wash sale replacement whose holding period starts earlier
+struct Pick {
+    acquired: chrono::NaiveDate,
+    left: Option<Decimal>,
+}
+
+// Index of the held lot the next part of a sell is matched against, and the pick that chose it
+fn select_lot(
+    stack: &[(usize, OpenLot)],
+    method: LotMethod,
+    picks: &[Pick],
+) -> Option<(usize, Option<usize>)> {
+    if stack.is_empty() {
+        return None;
+    }
+    // compares the price per share of two lots without dividing by a quantity that may be zero
+    let dearer = |a: &OpenLot, b: &OpenLot| a.cost_basis * b.quantity > b.cost_basis * a.quantity;
+    // ties go to the oldest lot
+    let first = |beats: &dyn Fn(&OpenLot, &OpenLot) -> bool| {
+        stack
+            .iter()
+            .position(|(_, lot)| !stack.iter().any(|(_, other)| beats(other, lot)))
+            .unwrap_or(0)
+    };
+    let index = match method {
+        LotMethod::Fifo => 0,
+        LotMethod::Lifo => stack.len() - 1,
+        LotMethod::Hifo => first(&dearer),
+        LotMethod::Lofo => first(&|a, b| dearer(b, a)),
+        // picks are used up one at a time, and anything not picked is matched FIFO
+        LotMethod::Specific => {
+            let picked = picks
+                .iter()
+                .enumerate()
+                .filter(|(_, pick)| pick.left != Some(Decimal::ZERO))
+                .find_map(|(p, pick)| {
+                    stack
+                        .iter()
+                        .position(|(_, lot)| exchange_date(lot.bought) == pick.acquired)
+                        .map(|index| (index, Some(p)))
+                });
+            return Some(picked.unwrap_or((0, None)));
+        }
+    };
+    Some((index, None))
+}
+
+// A change to the gain of a realized lot, reported as a Form 8949 adjustment
+#[derive(Debug, Clone)]
+pub enum Adjustment {
+    // Loss disallowed by a wash sale and added back to the gain (code W), and when the replacement
+    // buy that absorbs it was made
+    WashSale(Decimal, chrono::DateTime<chrono::Utc>),
+}
+
+// Shares of one lot that were sold
+#[derive(Debug, Clone)]
+pub struct RealizedLot {
+    pub symbol: String,
+    pub quantity: Decimal,
+    pub acquired: chrono::DateTime<chrono::Utc>,
+    pub disposed: chrono::DateTime<chrono::Utc>,
+    pub proceeds: Decimal,
+    pub cost_basis: Decimal,
+    pub fees: Decimal,
+    pub term: Term,
+    pub adjustments: Vec<Adjustment>,
+    // the shares came from an opening lot moved in from another broker
+    pub transferred_in: bool,
+}
+
+impl RealizedLot {
+    pub fn adjustment(&self) -> Decimal {
+        self.adjustments
+            .iter()
+            .map(|adjustment| match adjustment {
+                Adjustment::WashSale(disallowed, _) => disallowed,
+            })
+            .sum()
+    }
+
+    // Gain after fees and adjustments
+    pub fn gain(&self) -> Decimal {
+        self.proceeds - self.fees - self.cost_basis + self.adjustment()
+    }
+}
+
+// Shares of one lot still held
+#[derive(Debug, Clone)]
+pub struct OpenLot {
+    pub quantity: Decimal,
+    pub acquired: chrono::DateTime<chrono::Utc>,
+    // when the shares were bought, which is `acquired` unless a washed loss moved the holding
+    // period back
+    pub bought: chrono::DateTime<chrono::Utc>,
+    pub cost_basis: Decimal,
+    pub transferred_in: bool,
+    // line of the CSV with the buy the lot came from, none when it was not bought in the history
+    pub row: Option<u64>,
+}
+
+impl OpenLot {
+    // Takes shares off the lot along with their part of the cost basis. Whatever is left keeps
+    // the rest of the basis, so splitting never loses a fraction of a cent
+    fn split(&mut self, quantity: Decimal) -> OpenLot {
+        let cost_basis = if quantity == self.quantity {
+            self.cost_basis
+        } else {
+            self.cost_basis * quantity / self.quantity
+        };
+        self.quantity -= quantity;
+        self.cost_basis -= cost_basis;
+        OpenLot {
+            quantity,
+            acquired: self.acquired,
+            bought: self.bought,
+            cost_basis,
+            transferred_in: self.transferred_in,
+            row: self.row,
+        }
+    }
+}
+
+// Shares sold short and not covered yet
+#[derive(Debug, Clone)]
+pub struct ShortLot {
+    pub quantity: Decimal,
+    pub opened: chrono::DateTime<chrono::Utc>,
+    pub proceeds: Decimal,
+    pub fees: Decimal,
+}
+
+impl ShortLot {
+    // Takes shares off the short lot along with their part of the proceeds and fees
+    fn split(&mut self, quantity: Decimal) -> ShortLot {
+        let (proceeds, fees) = if quantity == self.quantity {
+            (self.proceeds, self.fees)
+        } else {
+            (
+                self.proceeds * quantity / self.quantity,
+                self.fees * quantity / self.quantity,
+            )
+        };
+        self.quantity -= quantity;
+        self.proceeds -= proceeds;
+        self.fees -= fees;
+        ShortLot {
+            quantity,
+            opened: self.opened,
+            proceeds,
+            fees,
+        }
+    }
+}
+
+// A loss on part of a sell that may be washed by a replacement buy
+struct Loss {
+    // which buy the sold shares came from
+    lot: usize,
+    // which of the sell's realized lots it is
+    realized: usize,
+    quantity: Decimal,
+    amount: Decimal,
+    held: chrono::Duration,
+}
+
+// A washed loss waiting for its replacement buy to be processed
+struct WashCarry {
+    quantity: Decimal,
+    disallowed: Decimal,
+    held: chrono::Duration,
+    sold: chrono::DateTime<chrono::Utc>,
+}
+
+// A sell that could not be matched to held shares, and where it is in the uploaded CSV
+#[derive(Debug, Clone)]
+pub struct UnmatchedSell {
+    pub row: u64,
+    pub date: chrono::DateTime<chrono::Utc>,
+    pub quantity: Decimal,
+    pub price: Decimal,
+    // shares of the sell no held lot was left for
+    pub unmatched: Decimal,
+    // shares held just before the sell
+    pub held: Decimal,
+    // the unmatched shares opened a short position instead, which is only right when the sell
+    // really was a short sale
+    pub short: bool,
+}
+
+// Shares a corporate action moved into a symbol after it was worked out, which happens when
+// renames go in a circle. The action is on line `row` of the corporate actions CSV
+#[derive(Debug, Clone)]
+pub struct LostTransfer {
+    pub row: u64,
+    pub from: String,
+    pub date: chrono::NaiveDate,
+    pub quantity: Decimal,
+}
+
 #[derive(Debug)]
 pub enum Information {
     // Buy of certain quantity, price, and time
-    Buy(f64, f64, chrono::DateTime<chrono::Utc>),
-    // Sell of certain quantity, price, and time, and how many buys are covered
-    Sell(f64, f64, chrono::DateTime<chrono::Utc>),
-    // Profit between sell and latest buy, and when it was processed
-    PriceDiff(f64, chrono::DateTime<chrono::Utc>),
-    // Time passic between sell and latest buy
-    TimeDiff(chrono::Duration),
-    // Total fee
-    Fees(f64),
-    // Remaining stocks left
-    Remaing(f64),
-    // A sell without a buy
-    WeirdSell,
-}
-
-pub type AllInfo = HashMap<String, Vec<Information>>;
+    Buy(Decimal, Decimal, chrono::DateTime<chrono::Utc>),
+    // Sell of certain quantity, price, and time
+    Sell(Decimal, Decimal, chrono::DateTime<chrono::Utc>),
+    // Sell of shares not held, opening a short position, of certain quantity, price, and time
+    SellShort(Decimal, Decimal, chrono::DateTime<chrono::Utc>),
+    // Buy closing a short position, of certain quantity, price, and time
+    Cover(Decimal, Decimal, chrono::DateTime<chrono::Utc>),
+    // A sell of more shares than were held, which the history is missing buys for
+    UnmatchedSell(UnmatchedSell),
+    // Shares moved over from another symbol that are left out of the position
+    LostTransfer(LostTransfer),
+    // Disallowed loss added to the basis of this many replacement shares, and the sell it came from
+    WashBasis(Decimal, Decimal, chrono::DateTime<chrono::Utc>),
+    // Every held share became this many shares on this date
+    Split(Decimal, chrono::NaiveDate),
+    // Every held share became this many shares of another symbol on this date
+    Moved(String, Decimal, chrono::NaiveDate),
+    // Every held share also got this many shares of another symbol, which took this percent of
+    // the basis
+    SpunOff(String, Decimal, Decimal, chrono::NaiveDate),
+    // Shares that came over from another symbol
+    Received(String, Decimal, chrono::NaiveDate),
+    // Shares held before the history starts, with their cost basis and when they were acquired
+    TransferredIn(Decimal, Decimal, chrono::NaiveDate),
+    // Shares paid out in cash at a price per share
+    Cash(Decimal, Decimal, chrono::DateTime<chrono::Utc>),
+}
 
+// Everything worked out for one symbol
+#[derive(Debug, Default)]
+pub struct Position {
+    // Buys and sells in the order they happened
+    pub informations: Vec<Information>,
+    pub realized: Vec<RealizedLot>,
+    pub open: Vec<OpenLot>,
+    pub short: Vec<ShortLot>,
+}
+
+impl Position {
+    pub fn gain(&self) -> Decimal {
+        self.realized.iter().map(RealizedLot::gain).sum()
+    }
+
+    pub fn quantity(&self) -> Decimal {
+        self.open.iter().map(|lot| lot.quantity).sum()
+    }
+
+    pub fn short_quantity(&self) -> Decimal {
+        self.short.iter().map(|lot| lot.quantity).sum()
+    }
+}
+
+pub type AllInfo = HashMap<String, Position>;
+
+// Lots a corporate action moved into another symbol
+struct Transfer {
+    from: String,
+    date: chrono::NaiveDate,
+    lot: OpenLot,
+    // line of the corporate action that moved it
+    row: u64,
+}
+
+// Held lots that did not come from a buy of the symbol itself
+const TRANSFERRED: usize = usize::MAX;
+
+// Symbols in an order where every symbol comes after the ones whose lots move into it. When
+// renames go in a circle there is no such order, and the lots moved into a symbol that was
+// already worked out are lost
+fn processing_order(
+    trades: &Trades,
+    actions: &CorporateActions,
+    opening: &OpeningLots,
+) -> Vec<String> {
+    let mut symbols: Vec<String> = trades
+        .keys()
+        .chain(actions.keys())
+        .chain(opening.keys())
+        .chain(
+            actions
+                .values()
+                .flatten()
+                .filter_map(|action| action.new_symbol.as_ref()),
+        )
+        .cloned()
+        .collect();
+    symbols.sort();
+    symbols.dedup();
+
+    let feeds = |from: &String, into: &String| {
+        from != into
+            && actions.get(from).is_some_and(|actions| {
+                actions
+                    .iter()
+                    .any(|action| action.new_symbol.as_ref() == Some(into))
+            })
+    };
+    let mut order = Vec::new();
+    while !symbols.is_empty() {
+        // a cycle of renames has no good order, so it is broken anywhere
+        let ready = symbols
+            .iter()
+            .position(|into| !symbols.iter().any(|from| feeds(from, into)))
+            .unwrap_or(0);
+        order.push(symbols.remove(ready));
+    }
+    order
+}
+
+// Pays out shares at `price` each, taking them from the held lots by `method`
+fn cash_out(
+    stack: &mut Vec<(usize, OpenLot)>,
+    symbol: &str,
+    quantity: Decimal,
+    price: Decimal,
+    date: chrono::DateTime<chrono::Utc>,
+    method: LotMethod,
+) -> Vec<RealizedLot> {
+    let mut quantity = quantity;
+    let mut realized = Vec::new();
+    while quantity > Decimal::ZERO {
+        let index = match select_lot(stack, method, &[]) {
+            Some((index, _)) => index,
+            None => break,
+        };
+        let held = &mut stack[index].1;
+        let matched = held.quantity.min(quantity);
+        let sold = held.split(matched);
+        if held.quantity.is_zero() {
+            stack.remove(index);
+        }
+        realized.push(RealizedLot {
+            symbol: symbol.to_string(),
+            quantity: matched,
+            acquired: sold.acquired,
+            disposed: date,
+            proceeds: price * matched,
+            cost_basis: sold.cost_basis,
+            fees: Decimal::ZERO,
+            term: holding_term(sold.acquired, date),
+            adjustments: Vec::new(),
+            transferred_in: sold.transferred_in,
+        });
+        quantity -= matched;
+    }
+    realized
+}
+
+// Closes short lots, oldest first, with shares just bought. A short sale is completed when the
+// shares are delivered, so its gain takes the holding period of the shares delivered, which is
+// short-term for anything but a wash sale replacement. The fees of the buy are in the basis of
+// the shares delivered
+fn cover(
+    shorts: &mut Vec<ShortLot>,
+    bought: &mut Vec<OpenLot>,
+    symbol: &str,
+    date: chrono::DateTime<chrono::Utc>,
+) -> Vec<RealizedLot> {
+    let mut realized = Vec::new();
+    while !shorts.is_empty() && !bought.is_empty() {
+        let matched = shorts[0].quantity.min(bought[0].quantity);
+        let short = shorts[0].split(matched);
+        let delivered = bought[0].split(matched);
+        if shorts[0].quantity.is_zero() {
+            shorts.remove(0);
+        }
+        if bought[0].quantity.is_zero() {
+            bought.remove(0);
+        }
+        realized.push(RealizedLot {
+            symbol: symbol.to_string(),
+            quantity: matched,
+            acquired: delivered.acquired,
+            disposed: date,
+            proceeds: short.proceeds,
+            cost_basis: delivered.cost_basis,
+            fees: short.fees,
+            term: holding_term(delivered.acquired, date),
+            adjustments: Vec::new(),
+            transferred_in: false,
+        });
+    }
+    realized
+}
+
+// Applies a corporate action to the held lots of its symbol. Lots that move to another symbol are
+// queued in `transfers`, and shares paid out in cash are realized
+fn apply_action(
+    action: &CorporateAction,
+    stack: &mut Vec<(usize, OpenLot)>,
+    method: LotMethod,
+    transfers: &mut HashMap<String, Vec<Transfer>>,
+    informations: &mut Vec<Information>,
+    realized: &mut Vec<RealizedLot>,
+) {
+    let date = exchange_open(action.date);
+    // the resulting shares and their symbol. A fraction of a share left over is paid in cash
+    let (mut lots, symbol) = match (action.action, &action.new_symbol) {
+        (ActionKind::Split, _) => {
+            for (_, lot) in stack.iter_mut() {
+                lot.quantity *= action.ratio;
+            }
+            informations.push(Information::Split(action.ratio, action.date));
+            (std::mem::take(stack), action.symbol.clone())
+        }
+        // the parser makes sure a cash merger has its price and a spin-off its allocation
+        (ActionKind::Merger, None) => {
+            let price = match action.cash {
+                Some(price) => price,
+                None => return,
+            };
+            let quantity = stack.iter().map(|(_, lot)| lot.quantity).sum();
+            informations.push(Information::Cash(quantity, price, date));
+            realized.append(&mut cash_out(
+                stack,
+                &action.symbol,
+                quantity,
+                price,
+                date,
+                method,
+            ));
+            return;
+        }
+        (ActionKind::Rename | ActionKind::Merger, Some(new_symbol)) => {
+            informations.push(Information::Moved(
+                new_symbol.clone(),
+                action.ratio,
+                action.date,
+            ));
+            let lots = stack
+                .drain(..)
+                .map(|(_, mut lot)| {
+                    lot.quantity *= action.ratio;
+                    (TRANSFERRED, lot)
+                })
+                .collect();
+            (lots, new_symbol.clone())
+        }
+        (ActionKind::Spinoff, Some(new_symbol)) => {
+            let allocation = match action.allocation {
+                Some(allocation) => allocation,
+                None => return,
+            };
+            informations.push(Information::SpunOff(
+                new_symbol.clone(),
+                action.ratio,
+                allocation,
+                action.date,
+            ));
+            // the spin-off keeps the holding period of the shares it came from
+            let lots = stack
+                .iter_mut()
+                .map(|(_, lot)| {
+                    let cost_basis = lot.cost_basis * allocation / Decimal::ONE_HUNDRED;
+                    lot.cost_basis -= cost_basis;
+                    let spun_off = OpenLot {
+                        quantity: lot.quantity * action.ratio,
+                        acquired: lot.acquired,
+                        bought: lot.bought,
+                        cost_basis,
+                        transferred_in: lot.transferred_in,
+                        row: None,
+                    };
+                    (TRANSFERRED, spun_off)
+                })
+                .collect();
+            (lots, new_symbol.clone())
+        }
+        (ActionKind::Rename | ActionKind::Spinoff, None) => return,
+    };
+
+    if let Some(price) = action.cash {
+        let fraction = lots
+            .iter()
+            .map(|(_, lot)| lot.quantity)
+            .sum::<Decimal>()
+            .fract();
+        if !fraction.is_zero() {
+            informations.push(Information::Cash(fraction, price, date));
+            realized.append(&mut cash_out(
+                &mut lots, &symbol, fraction, price, date, method,
+            ));
+        }
+    }
+    if symbol == action.symbol {
+        *stack = lots;
+    } else {
+        transfers
+            .entry(symbol)
+            .or_default()
+            .extend(lots.into_iter().map(|(_, lot)| Transfer {
+                from: action.symbol.clone(),
+                date: action.date,
+                lot,
+                row: action.row,
+            }));
+    }
+}
+
+// Every unmatched sell across all symbols, next to its symbol
+pub fn unmatched_sells(info: &AllInfo) -> impl Iterator<Item = (&String, &UnmatchedSell)> {
+    info.iter().flat_map(|(symbol, position)| {
+        position
+            .informations
+            .iter()
+            .filter_map(move |information| match information {
+                Information::UnmatchedSell(sell) => Some((symbol, sell)),
+                _ => None,
+            })
+    })
+}
+
+// Every lost transfer across all symbols
+pub fn lost_transfers(info: &AllInfo) -> impl Iterator<Item = (&String, &LostTransfer)> {
+    info.iter().flat_map(|(symbol, position)| {
+        position
+            .informations
+            .iter()
+            .filter_map(move |information| match information {
+                Information::LostTransfer(transfer) => Some((symbol, transfer)),
+                _ => None,
+            })
+    })
+}
+
+// Every realized lot across all symbols
+pub fn realized(info: &AllInfo) -> impl Iterator<Item = &RealizedLot> {
+    info.values().flat_map(|position| position.realized.iter())
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn parse(
     trades: &Trades,
     symbol_filter: String,
-    year: Option<chrono::DateTime<chrono::Utc>>,
+    year: Option<TaxYear>,
+    method: LotMethod,
+    short_sales: bool,
+    selections: &LotSelections,
+    actions: &CorporateActions,
+    opening: &OpeningLots,
 ) -> AllInfo {
     let mut result = AllInfo::new();
-    for (symbol, data) in trades {
-        if !symbol.contains(&symbol_filter) {
-            continue;
+    let mut transfers: HashMap<String, Vec<Transfer>> = HashMap::new();
+    // every symbol is worked out, as lots can move from one that is filtered out into one that
+    // is not
+    for symbol in processing_order(trades, actions, opening) {
+        let mut position = Position::default();
+        let informations = &mut position.informations;
+
+        let mut data = trades.get(&symbol).cloned().unwrap_or_default();
+        data.sort_by(|a, b| a.date.partial_cmp(&b.date).unwrap());
+        let in_year =
+            |date: chrono::DateTime<chrono::Utc>| year.is_none_or(|year| year.contains(date));
+
+        // How many shares of each buy can still absorb a washed loss
+        let mut replaceable: Vec<Decimal> = data
+            .iter()
+            .map(|d| {
+                if d.side == "buy" {
+                    d.quantity
+                } else {
+                    Decimal::ZERO
+                }
+            })
+            .collect();
+        let mut carries: HashMap<usize, Vec<WashCarry>> = HashMap::new();
+        // The splits of the symbol. A buy made after a split is in shares of after it, so they
+        // tell how many of those shares one share held before the split is
+        let splits: Vec<&CorporateAction> = actions
+            .get(&symbol)
+            .into_iter()
+            .flatten()
+            .filter(|action| action.action == ActionKind::Split)
+            .collect();
+        let split_between = |from: chrono::DateTime<chrono::Utc>,
+                             to: chrono::DateTime<chrono::Utc>| {
+            splits
+                .iter()
+                .filter(|split| exchange_date(from) < split.date && split.date <= exchange_date(to))
+                .map(|split| split.ratio)
+                .product::<Decimal>()
+        };
+        // The lot selections of every sell date. A sell that fills as several rows on one day
+        // uses them up together, so what is left of each is kept across the rows
+        let mut picks: HashMap<chrono::NaiveDate, Vec<Pick>> = HashMap::new();
+        for selection in selections.get(&symbol).into_iter().flatten() {
+            picks.entry(selection.sold).or_default().push(Pick {
+                acquired: selection.acquired,
+                left: selection.quantity,
+            });
         }
 
-        let mut informations = Vec::new();
+        let mut own_actions: Vec<&CorporateAction> = actions
+            .get(&symbol)
+            .map(|actions| actions.iter().collect())
+            .unwrap_or_default();
+        own_actions.sort_by_key(|action| action.date);
+        let mut own_actions = own_actions.into_iter().peekable();
+        let mut arrivals = transfers.remove(&symbol).unwrap_or_default();
+        arrivals.sort_by_key(|transfer| transfer.date);
+        let mut arrivals = arrivals.into_iter().peekable();
 
-        let mut data = data.clone();
-        data.sort_by(|a, b| a.date.partial_cmp(&b.date).unwrap());
-        // A stack to track buy and sell orders such that the sell goes exhausts the latests buys
-        // first
-        let mut stack = Vec::new();
-        for mut d in data {
+        // The buys still held, in the order they were made, next to their index in `data`.
+        // Which one a sell exhausts first depends on the lot method
+        let mut stack: Vec<(usize, OpenLot)> = Vec::new();
+        // Shares sold short, in the order they were sold
+        let mut shorts: Vec<ShortLot> = Vec::new();
+
+        // shares held before the first trade start off the stack, oldest first. An empty lot has
+        // nothing to sell
+        let mut opening_lots: Vec<_> = opening
+            .get(&symbol)
+            .into_iter()
+            .flatten()
+            .filter(|lot| lot.quantity > Decimal::ZERO)
+            .collect();
+        opening_lots.sort_by_key(|lot| lot.acquired);
+        for lot in opening_lots {
+            informations.push(Information::TransferredIn(
+                lot.quantity,
+                lot.cost_basis,
+                lot.acquired,
+            ));
+            let lot = OpenLot {
+                quantity: lot.quantity,
+                acquired: exchange_open(lot.acquired),
+                bought: exchange_open(lot.acquired),
+                cost_basis: lot.cost_basis,
+                transferred_in: true,
+                row: None,
+            };
+            stack.push((TRANSFERRED, lot));
+        }
+        // one more round after the last trade for corporate actions that came after it
+        for i in 0..=data.len() {
+            let today = data.get(i).map(|d| exchange_date(d.date));
+            let due = |date: chrono::NaiveDate| today.is_none_or(|today| date <= today);
+
+            // lots from another symbol keep their basis and when they were acquired
+            while let Some(transfer) = arrivals.next_if(|transfer| due(transfer.date)) {
+                let at = stack.partition_point(|(_, held)| held.acquired <= transfer.lot.acquired);
+                if in_year(exchange_open(transfer.date)) {
+                    informations.push(Information::Received(
+                        transfer.from,
+                        transfer.lot.quantity,
+                        transfer.date,
+                    ));
+                }
+                stack.insert(at, (TRANSFERRED, transfer.lot));
+            }
+            // corporate actions take effect before any trade made the same day. A split changes
+            // the share count of every held lot, but not its total basis or when it was acquired
+            while let Some(action) = own_actions.next_if(|action| due(action.date)) {
+                if action.action == ActionKind::Split {
+                    for quantity in &mut replaceable[..i] {
+                        *quantity *= action.ratio;
+                    }
+                    for short in &mut shorts {
+                        short.quantity *= action.ratio;
+                    }
+                    for carry in carries.values_mut().flatten() {
+                        carry.quantity *= action.ratio;
+                    }
+                }
+                let mut action_informations = Vec::new();
+                let mut realized = Vec::new();
+                apply_action(
+                    action,
+                    &mut stack,
+                    method,
+                    &mut transfers,
+                    &mut action_informations,
+                    &mut realized,
+                );
+                if in_year(exchange_open(action.date)) {
+                    informations.append(&mut action_informations);
+                    position.realized.append(&mut realized);
+                }
+            }
+
+            let d = match data.get(i) {
+                Some(d) => d,
+                None => break,
+            };
             let side = &d.side;
 
-            // we have a buy, so push it to into the stack
+            // we have a buy, so push it to into the stack. `fees` is the total of the order, and
+            // it is part of what the shares cost
             if side == "buy" {
-                if let Some(year) = year {
-                    if d.date >= year && d.date < year + chrono::Duration::days(365) {
+                let mut lot = OpenLot {
+                    quantity: d.quantity,
+                    acquired: d.date,
+                    bought: d.date,
+                    cost_basis: d.average_price * d.quantity + d.fees,
+                    transferred_in: false,
+                    row: Some(d.row),
+                };
+                let mut bought = Vec::new();
+                let mut wash_informations = Vec::new();
+                // shares replacing a washed loss take over its basis and holding period
+                for carry in carries.remove(&i).unwrap_or_default() {
+                    wash_informations.push(Information::WashBasis(
+                        carry.disallowed,
+                        carry.quantity,
+                        carry.sold,
+                    ));
+                    let mut replacement = lot.split(carry.quantity);
+                    replacement.cost_basis += carry.disallowed;
+                    replacement.acquired -= carry.held;
+                    bought.push(replacement);
+                }
+                if !lot.quantity.is_zero() {
+                    bought.push(lot);
+                }
+                // the shares bought cover any short position before they are held
+                let mut realized = cover(&mut shorts, &mut bought, &symbol, d.date);
+                let covered: Decimal = realized.iter().map(|lot| lot.quantity).sum();
+                if in_year(d.date) {
+                    if !covered.is_zero() {
+                        informations.push(Information::Cover(covered, d.average_price, d.date));
+                    }
+                    if covered != d.quantity {
                         informations.push(Information::Buy(
-                            d.quantity,
+                            d.quantity - covered,
                             d.average_price,
-                            d.date.clone(),
+                            d.date,
                         ));
                     }
-                } else {
-                    informations.push(Information::Buy(
-                        d.quantity,
-                        d.average_price,
-                        d.date.clone(),
-                    ));
+                    informations.append(&mut wash_informations);
+                    position.realized.append(&mut realized);
                 }
-                stack.push(d);
+                stack.extend(bought.into_iter().map(|lot| (i, lot)));
             } else {
                 // we have a sell
                 // keep poping from the stack until the quanity from the sell exhauts all the buy
                 // quantities
-                if let Some(year) = year {
-                    if d.date >= year && d.date < year + chrono::Duration::days(365) {
-                        informations.push(Information::Sell(d.quantity, d.average_price, d.date));
+                let picks = picks.entry(exchange_date(d.date)).or_default();
+                let mut losses = Vec::new();
+                let mut realized = Vec::new();
+                let held: Decimal = stack.iter().map(|(_, lot)| lot.quantity).sum();
+                // shares of the sell not matched to a held lot yet, and the fees not allocated to
+                // them yet
+                let mut quantity = d.quantity;
+                let mut fees = d.fees;
+                while quantity > Decimal::ZERO {
+                    let (index, pick) = match select_lot(&stack, method, picks) {
+                        Some(selected) => selected,
+                        None => break,
+                    };
+                    let (lot, held) = &mut stack[index];
+                    let lot = *lot;
+                    let mut matched = held.quantity.min(quantity);
+                    if let Some(left) = pick.and_then(|pick| picks[pick].left.as_mut()) {
+                        matched = matched.min(*left);
+                        *left -= matched;
+                    }
+                    // the rest of the lot stays held with its own basis and date
+                    let sold = held.split(matched);
+                    if held.quantity.is_zero() {
+                        stack.remove(index);
                     }
-                } else {
-                    informations.push(Information::Sell(d.quantity, d.average_price, d.date));
-                }
-                let mut process = || -> Option<()> {
-                    let mut prev_d = stack.pop()?;
-                    let mut quantity = d.quantity;
-
-                    loop {
-                        quantity = prev_d.quantity - quantity;
 
-                        let time_diff = d.date - prev_d.date;
-                        let price_diff = (d.average_price - prev_d.average_price) * prev_d.quantity;
+                    let fee = if matched == quantity {
+                        fees
+                    } else {
+                        d.fees * matched / d.quantity
+                    };
+                    let realized_lot = RealizedLot {
+                        symbol: symbol.clone(),
+                        quantity: matched,
+                        acquired: sold.acquired,
+                        disposed: d.date,
+                        proceeds: d.average_price * matched,
+                        cost_basis: sold.cost_basis,
+                        fees: fee,
+                        term: holding_term(sold.acquired, d.date),
+                        adjustments: Vec::new(),
+                        transferred_in: sold.transferred_in,
+                    };
+                    if realized_lot.gain() < Decimal::ZERO {
+                        losses.push(Loss {
+                            lot,
+                            realized: realized.len(),
+                            quantity: matched,
+                            amount: -realized_lot.gain(),
+                            held: d.date - sold.acquired,
+                        });
+                    }
+                    realized.push(realized_lot);
 
-                        let mut push = || {
-                            informations.push(Information::TimeDiff(time_diff));
+                    quantity -= matched;
+                    fees -= fee;
+                }
 
-                            informations.push(Information::PriceDiff(price_diff, d.date));
+                // what is left of the sell once nothing is held opens a short position
+                let shorted = if short_sales { quantity } else { Decimal::ZERO };
+                if in_year(d.date) {
+                    if shorted != d.quantity {
+                        informations.push(Information::Sell(
+                            d.quantity - shorted,
+                            d.average_price,
+                            d.date,
+                        ));
+                    }
+                    if !shorted.is_zero() {
+                        informations.push(Information::SellShort(shorted, d.average_price, d.date));
+                    }
+                }
+                // a missing buy throws off every later year too, so it is reported whatever the
+                // year. So is a short sale, which looks the same as a missing buy
+                if !quantity.is_zero() {
+                    informations.push(Information::UnmatchedSell(UnmatchedSell {
+                        row: d.row,
+                        date: d.date,
+                        quantity: d.quantity,
+                        price: d.average_price,
+                        unmatched: quantity,
+                        held,
+                        short: !shorted.is_zero(),
+                    }));
+                }
+                if !shorted.is_zero() {
+                    shorts.push(ShortLot {
+                        quantity: shorted,
+                        opened: d.date,
+                        proceeds: d.average_price * shorted,
+                        fees,
+                    });
+                }
 
-                            informations.push(Information::Fees(d.quantity * d.fees));
+                // A loss is washed when the same symbol is bought within the wash sale window of
+                // the sell. The loss moves into the basis of the replacement shares
+                for loss in losses {
+                    let mut left = loss.quantity;
+                    let mut amount = loss.amount;
+                    for j in 0..data.len() {
+                        if left.is_zero()
+                            || j == loss.lot
+                            || replaceable[j].is_zero()
+                            || !in_wash_window(d.date, data[j].date)
+                        {
+                            continue;
+                        }
+                        // an earlier buy only counts while some of it is still held. Its
+                        // unadjusted shares are the last entry split off from it. A later one
+                        // is compared in shares held now, and its carry is split along with them
+                        let position = stack.iter().rposition(|(lot, _)| *lot == j);
+                        let ratio = if j > i {
+                            split_between(d.date, data[j].date)
+                        } else {
+                            Decimal::ONE
                         };
-
-                        if let Some(year) = year {
-                            if d.date >= year && d.date < year + chrono::Duration::days(365) {
-                                push();
+                        let quantity = match position {
+                            _ if j > i => left.min(replaceable[j] / ratio),
+                            Some(position) => {
+                                left.min(replaceable[j]).min(stack[position].1.quantity)
                             }
+                            None => continue,
+                        };
+                        let disallowed = if quantity == left {
+                            amount
                         } else {
-                            push();
-                        }
+                            loss.amount * quantity / loss.quantity
+                        };
+                        replaceable[j] -= quantity * ratio;
+                        left -= quantity;
+                        amount -= disallowed;
+                        realized[loss.realized]
+                            .adjustments
+                            .push(Adjustment::WashSale(disallowed, data[j].date));
 
-                        // All the sell quantity have been exhausted
-                        if quantity >= 0.0 {
-                            break;
+                        let carry = WashCarry {
+                            quantity,
+                            disallowed,
+                            held: loss.held,
+                            sold: d.date,
+                        };
+                        if j > i {
+                            carries.entry(j).or_default().push(carry);
+                            continue;
                         }
-                        prev_d = stack.pop()?;
-                        quantity = quantity.abs();
-                    }
-
-                    // leave the remaining amout of buys quantity in the stack
-                    d.quantity = quantity;
-                    if quantity > 0.0 {
-                        stack.push(d.clone());
-                    }
-                    Some(())
-                };
-
-                if process().is_none() {
-                    if let Some(year) = year {
-                        if d.date >= year && d.date < year + chrono::Duration::days(365) {
-                            informations.push(Information::WeirdSell);
+                        // the replacement is already held, so split it off the lot it is part of
+                        let position = match position {
+                            Some(position) => position,
+                            None => continue,
+                        };
+                        let mut replacement = stack[position].1.split(carry.quantity);
+                        replacement.cost_basis += carry.disallowed;
+                        replacement.acquired -= carry.held;
+                        if stack[position].1.quantity.is_zero() {
+                            stack[position].1 = replacement;
+                        } else {
+                            stack.insert(position, (j, replacement));
+                        }
+                        if in_year(data[j].date) {
+                            informations.push(Information::WashBasis(
+                                carry.disallowed,
+                                carry.quantity,
+                                carry.sold,
+                            ));
                         }
-                    } else {
-                        informations.push(Information::WeirdSell);
                     }
                 }
-
-                //let res = process().unwrap_or("Selling more than owned?\n".to_string());
+                if in_year(d.date) {
+                    position.realized.append(&mut realized);
+                }
             }
         }
-        let mut remaining = 0.;
-        while let Some(last) = stack.pop() {
-            remaining += last.quantity;
-        }
-        informations.push(Information::Remaing(remaining));
+        position.open = stack.into_iter().map(|(_, lot)| lot).collect();
+        position.short = shorts;
 
-        // greater than 0 (1 since we push remaining value of stock always, even if 0-owned)
-        if informations.len() > 1 {
-            result
-                .entry(symbol.to_string())
-                .or_insert(Vec::new())
-                .append(&mut informations);
+        if symbol.contains(&symbol_filter)
+            && (!position.informations.is_empty() || !position.realized.is_empty())
+        {
+            result.insert(symbol, position);
+        }
+    }
+    // what is left was moved into a symbol that came earlier in the order, so it is reported
+    // rather than dropped without a word
+    for (symbol, lost) in transfers {
+        if !symbol.contains(&symbol_filter) {
+            continue;
+        }
+        let position = result.entry(symbol).or_default();
+        for transfer in lost {
+            position
+                .informations
+                .push(Information::LostTransfer(LostTransfer {
+                    row: transfer.row,
+                    from: transfer.from,
+                    date: transfer.date,
+                    quantity: transfer.lot.quantity,
+                }));
         }
     }
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csv_parser::{self, LotSelection, OpeningLot};
+    use rust_decimal_macros::dec;
+
+    fn trades(csv: &str) -> Trades {
+        let header = "symbol,date,order_type,side,fees,quantity,average_price\n";
+        csv_parser::parse(&format!("{}{}", header, csv)).unwrap()
+    }
+
+    fn position(
+        trades: &Trades,
+        method: LotMethod,
+        selections: &LotSelections,
+        opening: &OpeningLots,
+    ) -> Position {
+        let actions = CorporateActions::new();
+        let mut info = parse(
+            trades,
+            String::new(),
+            None,
+            method,
+            false,
+            selections,
+            &actions,
+            opening,
+        );
+        info.remove("A").unwrap()
+    }
+
+    fn date(date: &str) -> chrono::NaiveDate {
+        date.parse().unwrap()
+    }
+
+    fn fifo(csv: &str) -> Position {
+        position(
+            &trades(csv),
+            LotMethod::Fifo,
+            &LotSelections::new(),
+            &OpeningLots::new(),
+        )
+    }
+
+    #[test]
+    fn partial_sell_across_two_lots() {
+        let position = fifo(
+            "A,2021-01-04T15:00:00Z,market,buy,0,10,100\n\
+             A,2021-01-05T15:00:00Z,market,buy,0,10,120\n\
+             A,2021-02-01T15:00:00Z,market,sell,0,15,130\n",
+        );
+        assert_eq!(position.realized.len(), 2);
+        assert_eq!(position.realized[0].cost_basis, dec!(1000));
+        assert_eq!(position.realized[1].cost_basis, dec!(600));
+        // the rest of the second lot keeps its own basis and date
+        assert_eq!(position.open.len(), 1);
+        assert_eq!(position.open[0].quantity, dec!(5));
+        assert_eq!(position.open[0].cost_basis, dec!(600));
+        assert_eq!(
+            position.open[0].acquired.to_rfc3339(),
+            "2021-01-05T15:00:00+00:00"
+        );
+    }
+
+    #[test]
+    fn sell_of_exactly_one_lot() {
+        let position = fifo(
+            "A,2021-01-04T15:00:00Z,market,buy,0,10,100\n\
+             A,2021-01-05T15:00:00Z,market,buy,0,10,120\n\
+             A,2021-02-01T15:00:00Z,market,sell,0,10,130\n",
+        );
+        assert_eq!(position.realized.len(), 1);
+        assert_eq!(position.realized[0].cost_basis, dec!(1000));
+        assert_eq!(position.open.len(), 1);
+        assert_eq!(position.open[0].quantity, dec!(10));
+        assert_eq!(position.open[0].cost_basis, dec!(1200));
+    }
+
+    #[test]
+    fn fill_split_into_rows_with_the_same_time() {
+        let position = fifo(
+            "A,2021-01-04T15:00:00Z,market,buy,0,3,100.01\n\
+             A,2021-01-04T15:00:00Z,market,buy,0,4,100.02\n\
+             A,2021-02-01T15:00:00Z,market,sell,0,2,130\n\
+             A,2021-02-01T15:00:00Z,market,sell,0,2,130\n\
+             A,2021-02-01T15:00:00Z,market,sell,0,1,130\n",
+        );
+        let cost_basis: Vec<_> = position.realized.iter().map(|lot| lot.cost_basis).collect();
+        assert_eq!(
+            cost_basis,
+            vec![dec!(200.02), dec!(100.01), dec!(100.02), dec!(100.02)]
+        );
+        assert_eq!(position.open.len(), 1);
+        assert_eq!(position.open[0].quantity, dec!(2));
+        assert_eq!(position.open[0].cost_basis, dec!(200.04));
+    }
+
+    // a loss sale late on January 31st and a buy back early in the day, 30 or 31 calendar days
+    // later but less than that many 24 hour periods
+    fn wash_sale_after(buy_back: &str) -> Position {
+        let trades = trades(&format!(
+            "A,2023-01-03T15:00:00Z,market,buy,0,100,50\n\
+             A,2023-01-31T20:00:00Z,market,sell,0,100,40\n\
+             A,{},market,buy,0,100,41\n",
+            buy_back
+        ));
+        position(
+            &trades,
+            LotMethod::Fifo,
+            &LotSelections::new(),
+            &OpeningLots::new(),
+        )
+    }
+
+    #[test]
+    fn wash_sale_on_day_30() {
+        let position = wash_sale_after("2023-03-02T15:00:00Z");
+        assert_eq!(position.realized[0].adjustment(), dec!(1000));
+        assert_eq!(position.open[0].cost_basis, dec!(5100));
+    }
+
+    #[test]
+    fn no_wash_sale_on_day_31() {
+        let position = wash_sale_after("2023-03-03T15:00:00Z");
+        assert!(position.realized[0].adjustments.is_empty());
+        assert_eq!(position.open[0].cost_basis, dec!(4100));
+    }
+
+    #[test]
+    fn highest_cost_first_with_an_empty_lot() {
+        let trades = trades(
+            "A,2021-01-04T15:00:00Z,market,buy,0,10,100\n\
+             A,2021-01-05T15:00:00Z,market,buy,0,10,120\n\
+             A,2021-02-01T15:00:00Z,market,sell,0,15,130\n",
+        );
+        let empty = OpeningLot {
+            symbol: "A".to_string(),
+            quantity: Decimal::ZERO,
+            acquired: date("2020-01-02"),
+            cost_basis: dec!(50),
+        };
+        let opening = OpeningLots::from([("A".to_string(), vec![empty])]);
+        let position = position(&trades, LotMethod::Hifo, &LotSelections::new(), &opening);
+        let realized: Vec<_> = position
+            .realized
+            .iter()
+            .map(|lot| (lot.quantity, lot.cost_basis))
+            .collect();
+        assert_eq!(realized, vec![(dec!(10), dec!(1200)), (dec!(5), dec!(500))]);
+        assert_eq!(position.open.len(), 1);
+        assert_eq!(position.open[0].quantity, dec!(5));
+        assert_eq!(position.open[0].cost_basis, dec!(500));
+    }
+
+    #[test]
+    fn specific_identification_by_quantity() {
+        let trades = trades(
+            "A,2021-01-04T15:00:00Z,market,buy,0,10,100\n\
+             A,2021-01-04T16:00:00Z,market,buy,0,10,110\n\
+             A,2021-01-05T15:00:00Z,market,buy,0,10,120\n\
+             A,2021-02-01T15:00:00Z,market,sell,0,18,130\n",
+        );
+        let pick = |acquired, quantity| LotSelection {
+            symbol: "A".to_string(),
+            sold: date("2021-02-01"),
+            acquired: date(acquired),
+            quantity: Some(quantity),
+        };
+        let selections = LotSelections::from([(
+            "A".to_string(),
+            vec![pick("2021-01-05", dec!(4)), pick("2021-01-04", dec!(12))],
+        )]);
+        let position = position(
+            &trades,
+            LotMethod::Specific,
+            &selections,
+            &OpeningLots::new(),
+        );
+        // both buys of January 4th are picked, and the two shares nothing picked go FIFO
+        let realized: Vec<_> = position
+            .realized
+            .iter()
+            .map(|lot| (lot.quantity, lot.cost_basis))
+            .collect();
+        assert_eq!(
+            realized,
+            vec![
+                (dec!(4), dec!(480)),
+                (dec!(10), dec!(1000)),
+                (dec!(2), dec!(220)),
+                (dec!(2), dec!(220)),
+            ]
+        );
+        let open: Vec<_> = position
+            .open
+            .iter()
+            .map(|lot| (lot.quantity, lot.cost_basis))
+            .collect();
+        assert_eq!(open, vec![(dec!(6), dec!(660)), (dec!(6), dec!(720))]);
+    }
+
+    #[test]
+    fn specific_identification_across_a_split_fill() {
+        let trades = trades(
+            "A,2021-01-04T15:00:00Z,market,buy,0,10,100\n\
+             A,2021-01-05T15:00:00Z,market,buy,0,10,120\n\
+             A,2021-02-01T15:00:00Z,market,sell,0,5,130\n\
+             A,2021-02-01T15:00:00Z,market,sell,0,5,130\n",
+        );
+        let selections = LotSelections::from([(
+            "A".to_string(),
+            vec![LotSelection {
+                symbol: "A".to_string(),
+                sold: date("2021-02-01"),
+                acquired: date("2021-01-05"),
+                quantity: Some(dec!(5)),
+            }],
+        )]);
+        let position = position(
+            &trades,
+            LotMethod::Specific,
+            &selections,
+            &OpeningLots::new(),
+        );
+        // the second row of the fill finds the selection used up and goes FIFO
+        let realized: Vec<_> = position
+            .realized
+            .iter()
+            .map(|lot| (lot.quantity, lot.cost_basis))
+            .collect();
+        assert_eq!(realized, vec![(dec!(5), dec!(600)), (dec!(5), dec!(500))]);
+        let open: Vec<_> = position
+            .open
+            .iter()
+            .map(|lot| (lot.quantity, lot.cost_basis))
+            .collect();
+        assert_eq!(open, vec![(dec!(5), dec!(500)), (dec!(5), dec!(600))]);
+    }
+
+    #[test]
+    fn specific_identification_of_a_wash_sale_replacement() {
+        let trades = trades(
+            "A,2021-01-02T15:00:00Z,market,buy,0,5,50\n\
+             A,2021-01-04T15:00:00Z,market,buy,0,10,100\n\
+             A,2021-02-01T15:00:00Z,market,sell,0,10,90\n\
+             A,2021-02-10T15:00:00Z,market,buy,0,10,95\n\
+             A,2021-03-01T15:00:00Z,market,sell,0,10,100\n",
+        );
+        let pick = |sold, acquired| LotSelection {
+            symbol: "A".to_string(),
+            sold: date(sold),
+            acquired: date(acquired),
+            quantity: None,
+        };
+        let selections = LotSelections::from([(
+            "A".to_string(),
+            vec![
+                pick("2021-02-01", "2021-01-04"),
+                pick("2021-03-01", "2021-02-10"),
+            ],
+        )]);
+        let position = position(
+            &trades,
+            LotMethod::Specific,
+            &selections,
+            &OpeningLots::new(),
+        );
+        // half the loss is washed by the buy of February 10th. The shares replacing it are
+        // picked by the day they were bought, not by their earlier holding period
+        let realized: Vec<_> = position.realized[1..]
+            .iter()
+            .map(|lot| (exchange_date(lot.acquired), lot.quantity, lot.cost_basis))
+            .collect();
+        assert_eq!(
+            realized,
+            vec![
+                (date("2021-01-13"), dec!(5), dec!(525)),
+                (date("2021-02-10"), dec!(5), dec!(475)),
+            ]
+        );
+        assert_eq!(position.open.len(), 1);
+        assert_eq!(position.open[0].cost_basis, dec!(300));
+    }
+
+    // A loss on 10 shares, a 2-for-1 split and then a buy back of `shares` shares
+    fn wash_sale_across_a_split(shares: &str) -> Position {
+        let trades = trades(&format!(
+            "A,2021-01-04T15:00:00Z,market,buy,0,10,100\n\
+             A,2021-02-01T15:00:00Z,market,sell,0,10,80\n\
+             A,2021-02-15T15:00:00Z,market,buy,0,{},40\n",
+            shares
+        ));
+        let actions = CorporateActions::from([(
+            "A".to_string(),
+            vec![CorporateAction {
+                symbol: "A".to_string(),
+                date: date("2021-02-10"),
+                action: ActionKind::Split,
+                ratio: dec!(2),
+                new_symbol: None,
+                allocation: None,
+                cash: None,
+                row: 2,
+            }],
+        )]);
+        let mut info = parse(
+            &trades,
+            String::new(),
+            None,
+            LotMethod::Fifo,
+            false,
+            &LotSelections::new(),
+            &actions,
+            &OpeningLots::new(),
+        );
+        info.remove("A").unwrap()
+    }
+
+    #[test]
+    fn wash_sale_replaced_after_a_split() {
+        let position = wash_sale_across_a_split("20");
+        assert_eq!(position.realized[0].adjustment(), dec!(200));
+        let open: Vec<_> = position
+            .open
+            .iter()
+            .map(|lot| (lot.quantity, lot.cost_basis, exchange_date(lot.acquired)))
+            .collect();
+        assert_eq!(open, vec![(dec!(20), dec!(1000), date("2021-01-18"))]);
+    }
+
+    #[test]
+    fn wash_sale_partly_replaced_after_a_split() {
+        // 15 new shares replace 7.5 of the shares sold, so only 75% of the loss is washed
+        let position = wash_sale_across_a_split("15");
+        assert_eq!(position.realized[0].adjustment(), dec!(150));
+        let open: Vec<_> = position
+            .open
+            .iter()
+            .map(|lot| (lot.quantity, lot.cost_basis))
+            .collect();
+        assert_eq!(open, vec![(dec!(15), dec!(750))]);
+    }
+
+    #[test]
+    fn renames_in_a_circle() {
+        let trades = trades(
+            "A,2021-01-04T15:00:00Z,market,buy,0,10,100\n\
+             A,2023-01-04T15:00:00Z,market,sell,0,10,120\n",
+        );
+        let rename = |symbol: &str, date: &str, new_symbol: &str, row| CorporateAction {
+            symbol: symbol.to_string(),
+            date: self::date(date),
+            action: ActionKind::Rename,
+            ratio: dec!(1),
+            new_symbol: Some(new_symbol.to_string()),
+            allocation: None,
+            cash: None,
+            row,
+        };
+        let actions = CorporateActions::from([
+            ("A".to_string(), vec![rename("A", "2021-06-01", "B", 2)]),
+            ("B".to_string(), vec![rename("B", "2022-06-01", "A", 3)]),
+        ]);
+        let info = parse(
+            &trades,
+            String::new(),
+            None,
+            LotMethod::Fifo,
+            false,
+            &LotSelections::new(),
+            &actions,
+            &OpeningLots::new(),
+        );
+        // the shares that cannot be followed back are reported, not silently dropped
+        let lost: Vec<_> = lost_transfers(&info)
+            .map(|(symbol, transfer)| {
+                (
+                    symbol.as_str(),
+                    transfer.from.as_str(),
+                    transfer.quantity,
+                    transfer.row,
+                )
+            })
+            .collect();
+        assert_eq!(lost, vec![("A", "B", dec!(10), 3)]);
+    }
+
+    #[test]
+    fn buy_fees_in_the_basis() {
+        let position = fifo(
+            "A,2021-01-04T15:00:00Z,market,buy,5,10,100\n\
+             A,2021-02-01T15:00:00Z,market,sell,1,4,110\n",
+        );
+        let sold = &position.realized[0];
+        assert_eq!((sold.cost_basis, sold.fees), (dec!(402), dec!(1)));
+        assert_eq!(position.open[0].cost_basis, dec!(603));
+    }
+
+    #[test]
+    fn short_sale_covered_with_fees() {
+        let trades = trades(
+            "A,2021-01-04T15:00:00Z,market,sell,1,10,20\n\
+             A,2021-01-05T15:00:00Z,market,buy,2,4,15\n\
+             A,2021-01-06T15:00:00Z,market,buy,2,6,15\n",
+        );
+        let mut info = parse(
+            &trades,
+            String::new(),
+            None,
+            LotMethod::Fifo,
+            true,
+            &LotSelections::new(),
+            &CorporateActions::new(),
+            &OpeningLots::new(),
+        );
+        let position = info.remove("A").unwrap();
+        assert_eq!(position.realized.len(), 2);
+        // the fees of the sell are split by shares, and those of each buy are in its basis
+        let realized: Vec<_> = position
+            .realized
+            .iter()
+            .map(|lot| (lot.fees, lot.cost_basis))
+            .collect();
+        assert_eq!(realized, vec![(dec!(0.4), dec!(62)), (dec!(0.6), dec!(92))]);
+        assert!(position.short.is_empty());
+        // the short sale still shows up as a data problem to check
+        assert!(position.informations.iter().any(|information| matches!(
+            information,
+            Information::UnmatchedSell(UnmatchedSell { short: true, .. })
+        )));
+    }
+}
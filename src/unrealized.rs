@@ -0,0 +1,74 @@
+use crate::csv_parser::Prices;
+use crate::tax::{exchange_close, exchange_date, holding_term, AllInfo, Term};
+use rust_decimal::Decimal;
+
+// What one held or short lot would realize if closed at the snapshot price
+#[derive(Debug, Clone)]
+pub struct UnrealizedLot {
+    pub quantity: Decimal,
+    // when the lot was bought, or sold short
+    pub acquired: chrono::DateTime<chrono::Utc>,
+    pub short: bool,
+    pub gain: Decimal,
+    pub term: Term,
+}
+
+// The held lots of one symbol valued at its snapshot price
+#[derive(Debug, Clone)]
+pub struct Unrealized {
+    pub symbol: String,
+    pub price: Decimal,
+    pub as_of: chrono::NaiveDate,
+    pub lots: Vec<UnrealizedLot>,
+}
+
+impl Unrealized {
+    pub fn gain(&self, term: Term) -> Decimal {
+        self.lots
+            .iter()
+            .filter(|lot| lot.term == term)
+            .map(|lot| lot.gain)
+            .sum()
+    }
+}
+
+// Every symbol still held or short that has a price, valued as of the price's date, or `now`
+// when it has none. The term is what it would be if sold on that day, and closing a short is
+// always short-term
+pub fn unrealized(
+    info: &AllInfo,
+    prices: &Prices,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Vec<Unrealized> {
+    let mut unrealized: Vec<Unrealized> = info
+        .iter()
+        .filter(|(_, position)| !position.open.is_empty() || !position.short.is_empty())
+        .filter_map(|(symbol, position)| {
+            let price = prices.get(symbol)?;
+            let as_of = price.date.unwrap_or_else(|| exchange_date(now));
+            let sold = exchange_close(as_of);
+            let held = position.open.iter().map(|lot| UnrealizedLot {
+                quantity: lot.quantity,
+                acquired: lot.acquired,
+                short: false,
+                gain: price.price * lot.quantity - lot.cost_basis,
+                term: holding_term(lot.acquired, sold),
+            });
+            let short = position.short.iter().map(|lot| UnrealizedLot {
+                quantity: lot.quantity,
+                acquired: lot.opened,
+                short: true,
+                gain: lot.proceeds - lot.fees - price.price * lot.quantity,
+                term: Term::Short,
+            });
+            Some(Unrealized {
+                symbol: symbol.clone(),
+                price: price.price,
+                as_of,
+                lots: held.chain(short).collect(),
+            })
+        })
+        .collect();
+    unrealized.sort_by(|a, b| a.symbol.cmp(&b.symbol));
+    unrealized
+}